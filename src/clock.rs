@@ -0,0 +1,62 @@
+use x86_64::instructions::port::Port;
+
+/// Input clock of the programmable interval timer (PIT)
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+
+/// Measures elapsed time using channel 0 of the programmable interval timer.
+///
+/// The counter wraps around every 65536 PIT ticks (~55ms), so `elapsed` must be called more often
+/// than that to measure time accurately.
+pub struct Clock {
+    last: u16,
+}
+
+impl Clock {
+    /// Programs PIT channel 0 as a free running counter and starts measuring time from now
+    pub fn new() -> Clock {
+        let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+        let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+
+        unsafe {
+            // Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), reload value 65536
+            command.write(0x34);
+            channel_0.write(0);
+            channel_0.write(0);
+        }
+
+        Clock {
+            last: read_counter(),
+        }
+    }
+
+    /// Returns the number of PIT ticks elapsed since the previous call
+    pub fn elapsed(&mut self) -> u32 {
+        let now = read_counter();
+        // The counter counts down
+        let elapsed = self.last.wrapping_sub(now);
+        self.last = now;
+        elapsed as u32
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new()
+    }
+}
+
+fn read_counter() -> u16 {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+
+    unsafe {
+        // Latch the current count of channel 0, then read it low byte first
+        command.write(0x00);
+        let low = channel_0.read() as u16;
+        let high = channel_0.read() as u16;
+        high << 8 | low
+    }
+}
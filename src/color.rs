@@ -20,6 +20,47 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// Returns the color with the given index in the default VGA palette, `None` above 15
+    pub fn from_u8(value: u8) -> Option<Color> {
+        let color = match value {
+            0 => Color::Black,
+            1 => Color::Blue,
+            2 => Color::Green,
+            3 => Color::Cyan,
+            4 => Color::Red,
+            5 => Color::Magenta,
+            6 => Color::Brown,
+            7 => Color::LightGray,
+            8 => Color::DarkGray,
+            9 => Color::LightBlue,
+            10 => Color::LightGreen,
+            11 => Color::LightCyan,
+            12 => Color::LightRed,
+            13 => Color::Pink,
+            14 => Color::Yellow,
+            15 => Color::White,
+            _ => return None,
+        };
+        Some(color)
+    }
+
+    /// Returns the index of the color in the default VGA palette, the same as `color as u8`
+    pub fn to_vga(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns a color which is readable on this one, e.g. for text drawn on top of it:
+    /// white on the dark colors, black on the light ones
+    pub fn contrasting(self) -> Color {
+        match self {
+            Color::Black | Color::Blue | Color::Green | Color::Cyan | Color::Red | Color::Magenta | Color::Brown
+            | Color::DarkGray => Color::White,
+            _ => Color::Black,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
@@ -29,3 +70,36 @@ impl ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_vga_index() {
+        for value in 0..16 {
+            let color = Color::from_u8(value).unwrap();
+            assert_eq!(color.to_vga(), value);
+            assert_eq!(color as u8, value);
+        }
+        assert_eq!(Color::from_u8(16), None);
+        assert_eq!(Color::from_u8(0xFF), None);
+    }
+
+    #[test]
+    fn contrasting_colors_are_readable() {
+        assert_eq!(Color::White.contrasting(), Color::Black);
+        assert_eq!(Color::Black.contrasting(), Color::White);
+        assert_eq!(Color::Blue.contrasting(), Color::White);
+        assert_eq!(Color::Yellow.contrasting(), Color::Black);
+        assert_eq!(Color::DarkGray.contrasting(), Color::White);
+        assert_eq!(Color::LightGray.contrasting(), Color::Black);
+
+        // The contrasting color of a contrasting color is contrasting again
+        for value in 0..16 {
+            let color = Color::from_u8(value).unwrap();
+            assert_ne!(color.contrasting(), color);
+            assert_eq!(color.contrasting().contrasting().contrasting(), color.contrasting());
+        }
+    }
+}
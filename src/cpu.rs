@@ -1,10 +1,22 @@
-use crate::display::Display;
-use crate::keyboard::Keyboard;
+use core::fmt;
+
+use crate::disasm::{self, Instruction, INSTRUCTION_KINDS};
+use crate::display::{Display, BIG_FONT_BASE};
+use crate::input::InputSource;
+use crate::quirks::Quirks;
 use crate::ram::Ram;
+use crate::renderer::Renderer;
+use crate::sound::DEFAULT_PITCH;
 
 ///
 /// CHIP-8 memory map
 ///
+/// +---------------+= 0xFFFF (65535) End of XO-CHIP extended RAM
+/// |               |
+/// |  0x1000 to    |
+/// |    0xFFFF     |
+/// | XO-CHIP only  |
+/// |               |
 /// +---------------+= 0xFFF (4095) End of Chip-8 RAM
 /// |               |
 /// |               |
@@ -42,7 +54,7 @@ pub struct Cpu {
     /// Chip-8 allows for up to 16 levels of nested subroutines.
     pub stack: [u16; 16],
 
-    /// stack pointer
+    /// stack pointer, points to the first free slot of the stack
     pub sp: u8,
 
     /// Delay timer
@@ -50,11 +62,121 @@ pub struct Cpu {
 
     /// Sound timer
     pub st: u8,
+
+    /// XO-CHIP pitch register, the pitch of the beep
+    pitch: u8,
+
+    /// Interpreter specific behaviours
+    quirks: Quirks,
+
+    /// Why the program ended, there is nothing left to execute
+    halt: Option<HaltReason>,
+
+    /// Record the executed opcodes in the trace log
+    trace: bool,
+
+    /// The last executed opcodes, oldest first
+    trace_log: [u16; TRACE_SIZE],
+
+    /// Number of opcodes in the trace log
+    trace_len: usize,
+
+    /// Seed of the random number generator, restored on reset
+    seed: u64,
+
+    /// State of the xorshift random number generator used by RND
+    rng: u64,
+
+    /// Number of instructions executed since the last reset
+    cycles: u64,
+
+    /// Fx0A is waiting for a key
+    waiting_key: bool,
+
+    /// A sprite was drawn since the last 60Hz tick, for the `display_wait` quirk
+    drawn_this_frame: bool,
+
+    /// Address of the font in memory
+    font_base: u16,
+
+    /// Count the executed instructions by kind in the coverage histogram
+    profile: bool,
+
+    /// Number of executed instructions, indexed by `Instruction::kind`
+    coverage: [u64; INSTRUCTION_KINDS],
+
+    /// Unknown opcodes fail with `UnknownOpcode`, otherwise they are logged and skipped
+    strict_opcodes: bool,
+
+    /// Address and opcode of the last unknown opcodes skipped in lenient mode, oldest first
+    unknown_log: [(u16, u16); UNKNOWN_LOG_SIZE],
+
+    /// Number of entries in the unknown opcode log
+    unknown_len: usize,
+}
+
+/// Number of opcodes kept in the trace log
+pub const TRACE_SIZE: usize = 64;
+
+/// Number of unknown opcodes kept in the log of the lenient mode
+pub const UNKNOWN_LOG_SIZE: usize = 16;
+
+/// Seed of the random number generator when none is given
+pub const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+/// Estimated cost of the instructions in cycles, indexed by the highest nibble of the opcode.
+/// A simple instruction costs 1 cycle, CALL pushes to the stack and DRW is by far the slowest.
+const OPCODE_COSTS: [u8; 16] = [1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 4, 1, 1];
+
+/// Returns the estimated number of cycles the instruction takes, so slow instructions can be paced against fast ones
+pub fn opcode_cost(opcode: u16) -> u8 {
+    match opcode {
+        // Clearing and scrolling touches the whole screen like a large sprite
+        0x00E0 | 0x00FB | 0x00FC | 0x00C0..=0x00CF => 4,
+        _ => OPCODE_COSTS[(opcode >> 12) as usize],
+    }
+}
+
+/// The way a program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The program jumped to the jump instruction itself, the usual ending of a CHIP-8 program
+    SelfJump,
+
+    /// The program executed 00FD (EXIT)
+    Exit,
 }
 
-fn read_word(memory: [u8; 4096], index: u16) -> u16 {
-    let i = index as usize;
-    (memory[i] as u16) << 8 | (memory[i + 1] as u16)
+/// Errors that can occur while executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// RET was executed while the stack was empty
+    StackUnderflow,
+
+    /// CALL was executed while the stack was full
+    StackOverflow,
+
+    /// An instruction tried to access memory past the end of the RAM, starting at the given address
+    MemoryOutOfBounds(u16),
+
+    /// The given opcode is not a valid instruction
+    UnknownOpcode(u16),
+}
+
+/// Outcome of a single executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepResult {
+    /// The executed opcode
+    pub opcode: u16,
+
+    /// The program counter after the instruction was executed
+    pub pc: u16,
+
+    /// The instruction cleared, scrolled or drew on the display
+    pub draw: bool,
+
+    /// The instruction set the delay or the sound timer
+    pub timer: bool,
 }
 
 impl Cpu {
@@ -67,9 +189,36 @@ impl Cpu {
             sp: 0,
             dt: 0,
             st: 0,
+            pitch: DEFAULT_PITCH,
+            quirks: Quirks::new(),
+            halt: None,
+            trace: false,
+            trace_log: [0; TRACE_SIZE],
+            trace_len: 0,
+            seed: DEFAULT_SEED,
+            rng: DEFAULT_SEED,
+            cycles: 0,
+            waiting_key: false,
+            drawn_this_frame: false,
+            font_base: 0,
+            profile: false,
+            coverage: [0; INSTRUCTION_KINDS],
+            strict_opcodes: true,
+            unknown_log: [(0, 0); UNKNOWN_LOG_SIZE],
+            unknown_len: 0,
         }
     }
 
+    /// Sets the interpreter quirks affecting how instructions are executed
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Returns the interpreter quirks in use
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
     pub fn reset(&mut self) {
         self.i = 0;
         self.pc = 0x200;
@@ -78,230 +227,513 @@ impl Cpu {
         self.sp = 0;
         self.dt = 0;
         self.st = 0;
+        self.pitch = DEFAULT_PITCH;
+        self.halt = None;
+        self.rng = self.seed;
+        self.cycles = 0;
+        self.waiting_key = false;
+        self.drawn_this_frame = false;
+    }
+
+    /// Seeds the random number generator used by RND, so the random numbers are reproducible.
+    /// The generator is reset to this seed on every reset. The xorshift generator can't use 0,
+    /// so a seed of 0 is replaced by `DEFAULT_SEED`.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.seed = if seed == 0 { DEFAULT_SEED } else { seed };
+        self.rng = self.seed;
+    }
+
+    /// Returns the seed of the random number generator
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the state of the random number generator, e.g. to save it
+    pub fn rng_state(&self) -> u64 {
+        self.rng
+    }
+
+    /// Restores the state of the random number generator returned by `rng_state`.
+    /// The xorshift generator can't use 0, so a state of 0 restarts the generator from the seed.
+    pub fn set_rng_state(&mut self, state: u64) {
+        self.rng = if state == 0 { self.seed } else { state };
+    }
+
+    /// Returns the next random byte, using xorshift64
+    fn random_byte(&mut self) -> u8 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 56) as u8
+    }
+
+    /// Returns the XO-CHIP pitch of the beep, see `sound::pitch_frequency`
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    /// Sets the XO-CHIP pitch of the beep, e.g. when restoring a save
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+    }
+
+    /// Returns true if the program ended by jumping to itself or by EXIT
+    pub fn is_halted(&self) -> bool {
+        self.halt.is_some()
+    }
+
+    /// Returns why the program ended, `None` while it's running
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halt
+    }
+
+    /// Sets why the program ended, e.g. when restoring a state. `None` resumes a halted program.
+    pub fn set_halt_reason(&mut self, halt: Option<HaltReason>) {
+        self.halt = halt;
+    }
+
+    /// Returns true if Fx0A is waiting for a key
+    pub fn is_waiting_key(&self) -> bool {
+        self.waiting_key
+    }
+
+    /// Sets whether Fx0A is waiting for a key, e.g. when restoring a state
+    pub fn set_waiting_key(&mut self, waiting: bool) {
+        self.waiting_key = waiting;
+    }
+
+    /// Decrements the delay and sound timers by one if they are nonzero.
+    /// Both timers count down at 60Hz, so this should be called 60 times per second.
+    /// This is also the frame boundary the `display_wait` quirk waits for.
+    pub fn tick_timers(&mut self) {
+        self.drawn_this_frame = false;
+
+        if self.dt > 0 {
+            self.dt -= 1;
+        }
+
+        if self.st > 0 {
+            self.st -= 1;
+        }
+    }
+
+    /// Enables or disables recording the executed opcodes in the trace log.
+    /// The log is cleared when tracing is enabled.
+    pub fn set_trace(&mut self, enabled: bool) {
+        if enabled && !self.trace {
+            self.trace_len = 0;
+        }
+        self.trace = enabled;
+    }
+
+    /// Returns the last executed opcodes (up to `TRACE_SIZE`) recorded while tracing, oldest first
+    pub fn trace_log(&self) -> &[u16] {
+        &self.trace_log[..self.trace_len]
     }
 
-    pub fn execute_cycle(&mut self, ram: &mut Ram, keyboard: &mut Keyboard, display: &mut Display) {
-        let opcode = read_word(ram.memory, self.pc);
-        self.pc += 2;
-        self.process_opcode(opcode, ram, keyboard, display);
+    /// Chooses what happens on an unknown opcode. In strict mode, the default, it fails with `UnknownOpcode`.
+    /// Otherwise it's executed as a no-op and logged, so the features a ROM needs from other variants can be found.
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.strict_opcodes = strict;
     }
 
-    fn process_opcode(&mut self, opcode: u16, ram: &mut Ram, keyboard: &mut Keyboard, display: &mut Display) {
-        match opcode {
-            0x00E0 => {
-                // 00E0 - CLS
-                // Clear the display.
-                display.clear();
+    /// Returns the address and the opcode of the last unknown opcodes (up to `UNKNOWN_LOG_SIZE`)
+    /// skipped in lenient mode, oldest first
+    pub fn unknown_opcodes(&self) -> &[(u16, u16)] {
+        &self.unknown_log[..self.unknown_len]
+    }
+
+    /// Sets the address of the font in memory, used by Fx29
+    pub fn set_font_base(&mut self, base: u16) {
+        self.font_base = base;
+    }
+
+    /// Enables or disables counting the executed instructions by kind.
+    /// The counts are cleared when profiling is enabled.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        if enabled && !self.profile {
+            self.coverage = [0; INSTRUCTION_KINDS];
+        }
+        self.profile = enabled;
+    }
+
+    /// Returns the number of instructions executed while profiling, indexed by `Instruction::kind`
+    pub fn coverage(&self) -> &[u64; INSTRUCTION_KINDS] {
+        &self.coverage
+    }
+
+    /// Returns the Vx registers
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    /// Returns the program counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Returns the index register
+    pub fn index(&self) -> u16 {
+        self.i
+    }
+
+    /// Returns the Vx register, or `None` if x is not a register
+    pub fn register(&self, x: u8) -> Option<u8> {
+        self.v.get(x as usize).copied()
+    }
+
+    /// Sets the Vx register for a debugger, returns false if x is not a register
+    pub fn set_register(&mut self, x: u8, value: u8) -> bool {
+        match self.v.get_mut(x as usize) {
+            Some(register) => {
+                *register = value;
+                true
             }
-            0x00EE => {
-                // 00EE - RET
-                // Return from a subroutine.
-                // The interpreter sets the program counter to the address at the top of the stack,
-                // then subtracts 1 from the stack pointer.
-                self.pc = self.stack[self.sp as usize];
-                self.sp -= 1;
+            None => false,
+        }
+    }
+
+    /// Sets the index register for a debugger
+    pub fn set_i(&mut self, value: u16) {
+        self.i = value;
+    }
+
+    /// Sets the program counter for a debugger, e.g. to run a subroutine in isolation
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    /// Returns the number of instructions executed since the last reset
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Sets the number of executed instructions, e.g. when restoring a state
+    pub fn set_cycle_count(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    pub fn execute_cycle<R: Renderer, I: InputSource>(&mut self, ram: &mut Ram, input: &mut I, display: &mut Display<R>) -> Result<(), CpuError> {
+        self.step(ram, input, display).map(|_| ())
+    }
+
+    /// Fetches and executes exactly one instruction.
+    /// A program running off the end of the memory (past 0xFFE in classic mode) fails with `MemoryOutOfBounds`.
+    pub fn step<R: Renderer, I: InputSource>(&mut self, ram: &mut Ram, input: &mut I, display: &mut Display<R>) -> Result<StepResult, CpuError> {
+        let address = self.pc;
+        let opcode = ram.read_u16(self.pc).ok_or(CpuError::MemoryOutOfBounds(self.pc))?;
+        self.pc = self.pc.wrapping_add(2);
+        self.cycles = self.cycles.wrapping_add(1);
+
+        if self.trace {
+            if self.trace_len == TRACE_SIZE {
+                self.trace_log.copy_within(1.., 0);
+                self.trace_len -= 1;
+            }
+            self.trace_log[self.trace_len] = opcode;
+            self.trace_len += 1;
+        }
+
+        match self.process_opcode(opcode, ram, input, display) {
+            Err(CpuError::UnknownOpcode(_)) if !self.strict_opcodes => {
+                if self.unknown_len == UNKNOWN_LOG_SIZE {
+                    self.unknown_log.copy_within(1.., 0);
+                    self.unknown_len -= 1;
+                }
+                self.unknown_log[self.unknown_len] = (address, opcode);
+                self.unknown_len += 1;
+            }
+            result => result?,
+        }
+
+        Ok(StepResult {
+            opcode,
+            pc: self.pc,
+            draw: matches!(opcode, 0x00C0..=0x00CF | 0x00E0 | 0x00FB | 0x00FC | 0x00FE | 0x00FF) || opcode & 0xF000 == 0xD000,
+            timer: opcode & 0xF0FF == 0xF015 || opcode & 0xF0FF == 0xF018,
+        })
+    }
+
+    /// Skips the next instruction, the XO-CHIP long load is skipped as a whole
+    fn skip(&mut self, ram: &Ram) {
+        if ram.read_u16(self.pc) == Some(0xF000) {
+            self.pc = self.pc.wrapping_add(2);
+        }
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn process_opcode<R: Renderer, I: InputSource>(&mut self, opcode: u16, ram: &mut Ram, input: &mut I, display: &mut Display<R>) -> Result<(), CpuError> {
+        // Every instruction is decoded from the same nibbles:
+        // nnn - the lowest 12 bits, kk - the lowest 8 bits, n - the lowest 4 bits,
+        // x - the lower 4 bits of the high byte, y - the upper 4 bits of the low byte.
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as u8;
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+
+        if self.profile {
+            // The address of the long load is in the next word, but only the kind of the instruction matters here
+            let instruction = if opcode == 0xF000 { Some(Instruction::LdILong(0)) } else { disasm::decode(opcode) };
+            if let Some(instruction) = instruction {
+                self.coverage[instruction.kind()] += 1;
             }
-            0x1000..=0x1FFF => {
+        }
+
+        match opcode >> 12 {
+            0x0 => match opcode {
+                0x00E0 => {
+                    // 00E0 - CLS
+                    // Clear the display.
+                    display.clear();
+                }
+                0x00EE => {
+                    // 00EE - RET
+                    // Return from a subroutine.
+                    // The interpreter subtracts 1 from the stack pointer, then sets the program counter
+                    // to the address at the top of the stack.
+                    if self.sp == 0 {
+                        return Err(CpuError::StackUnderflow);
+                    }
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize];
+                }
+                0x00FB => {
+                    // 00FB - SCR (SUPER-CHIP)
+                    // Scroll the display right by 4 pixels.
+                    display.scroll_right();
+                }
+                0x00FC => {
+                    // 00FC - SCL (SUPER-CHIP)
+                    // Scroll the display left by 4 pixels.
+                    display.scroll_left();
+                }
+                0x00FD => {
+                    // 00FD - EXIT (SUPER-CHIP)
+                    // Exit the interpreter.
+                    //
+                    // The CPU is halted, the timers keep running.
+                    self.halt = Some(HaltReason::Exit);
+                }
+                0x00FE => {
+                    // 00FE - LOW
+                    // Disable the SUPER-CHIP high resolution mode, switching back to 64x32.
+                    display.set_hires(false);
+                }
+                0x00FF => {
+                    // 00FF - HIGH
+                    // Enable the SUPER-CHIP 128x64 high resolution mode.
+                    display.set_hires(true);
+                }
+                _ if opcode & 0xFFF0 == 0x00C0 => {
+                    // 00Cn - SCD nibble (SUPER-CHIP)
+                    // Scroll the display down by n pixels.
+                    display.scroll_down(n as usize);
+                }
+                _ => {
+                    // 0nnn - SYS addr
+                    // Jump to a machine code routine at nnn.
+                    //
+                    // This instruction is only used on the old computers on which Chip-8 was originally implemented.
+                    // It is ignored by modern interpreters.
+                }
+            },
+            0x1 => {
                 // 1nnn - JP addr
-                // 1nnn - JP addr - Jump to location nnn.
+                // Jump to location nnn.
                 // The interpreter sets the program counter to nnn.
-                self.pc = opcode & 0x0FFF;
+                //
+                // Programs usually end by jumping to the jump instruction itself, this is detected to
+                // halt the CPU instead of spinning forever.
+                if nnn == self.pc.wrapping_sub(2) {
+                    self.halt = Some(HaltReason::SelfJump);
+                }
+                self.pc = nnn;
             }
-            0x2000..=0x2FFF => {
+            0x2 => {
                 // 2nnn - CALL addr
                 // Call subroutine at nnn.
-                // The interpreter increments the stack pointer, then puts the current PC on the top of the stack.
+                // The interpreter puts the current PC on the top of the stack, then increments the stack pointer.
                 // The PC is then set to nnn.
-                self.sp += 1;
+                if self.sp as usize >= self.stack.len() {
+                    return Err(CpuError::StackOverflow);
+                }
                 self.stack[self.sp as usize] = self.pc;
-                self.pc = opcode & 0x0FFF;
+                self.sp += 1;
+                self.pc = nnn;
             }
-            0x3000..=0x3FFF => {
+            0x3 => {
                 // 3xkk - SE Vx, byte
                 // Skip next instruction if Vx = kk.
                 // The interpreter compares register Vx to kk, and if they are equal, increments the program counter by 2.
-                let x = (opcode & 0x0F00) >> 8;
-                let value = (opcode & 0x00FF) as u8;
-                if self.v[x as usize] == value {
-                    self.pc += 2;
+                if self.v[x] == kk {
+                    self.skip(ram);
                 }
             }
-            0x4000..=0x4FFF => {
+            0x4 => {
                 // 4xkk - SNE Vx, byte
                 // Skip next instruction if Vx != kk.
                 // The interpreter compares register Vx to kk, and if they are not equal, increments the program counter by 2.
-                let x = (opcode & 0x0F00) >> 8;
-                let value = (opcode & 0x00FF) as u8;
-                if self.v[x as usize] != value {
-                    self.pc += 2;
+                if self.v[x] != kk {
+                    self.skip(ram);
                 }
             }
-            0x5000..=0x5FFF => {
+            0x5 if n == 0 => {
                 // 5xy0 - SE Vx, Vy
                 // Skip next instruction if Vx = Vy.
                 // The interpreter compares register Vx to register Vy, and if they are equal, increments the program counter by 2.
-                let x = (opcode & 0x0F00) >> 8;
-                let y = (opcode & 0x00F0) >> 4;
-                if self.v[x as usize] == self.v[y as usize] {
-                    self.pc += 2;
+                if self.v[x] == self.v[y] {
+                    self.skip(ram);
                 }
             }
-            0x6000..=0x6FFF => {
+            0x6 => {
                 // 6xkk - LD Vx, byte
                 // Set Vx = kk.
                 // The interpreter puts the value kk into register Vx.
-                let x = (opcode & 0x0F00) >> 8;
-                let kk = (opcode & 0x00FF) as u8;
-                self.v[x as usize] = kk;
+                self.v[x] = kk;
             }
-            0x7000..=0x7FFF => {
+            0x7 => {
                 // 7xkk - ADD Vx, byte
                 // Set Vx = Vx + kk.
                 // Adds the value kk to the value of register Vx, then stores the result in Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let kk = (opcode & 0x00FF) as u8;
                 self.v[x] = self.v[x].wrapping_add(kk);
             }
-            0x8000..=0x8FF0 => {
-                // 8xy0 - LD Vx, Vy
-                // Set Vx = Vy.
-                // Stores the value of register Vy in register Vx.
-                let x = (opcode & 0x0F00) >> 8;
-                let y = (opcode & 0x00F0) >> 4;
-                self.v[x as usize] = self.v[y as usize];
-            }
-            0x8001..=0x8FF1 => {
-                // 8xy1 - OR Vx, Vy
-                // Set Vx = Vx OR Vy.
-                //
-                // Performs a bitwise OR on the values of Vx and Vy, then stores the result in Vx.
-                // A bitwise OR compares the corresponding bits from two values, and if either bit is 1,
-                // then the same bit in the result is also 1. Otherwise, it is 0.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                self.v[x] = self.v[x] | self.v[y];
-            }
-            0x8002..=0x8FF2 => {
-                // 8xy2 - AND Vx, Vy
-                // Set Vx = Vx AND Vy.
-                //
-                // Performs a bitwise AND on the values of Vx and Vy, then stores the result in Vx.
-                // A bitwise AND compares the corrseponding bits from two values, and if both bits are 1,
-                // then the same bit in the result is also 1. Otherwise, it is 0.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                self.v[x] = self.v[x] & self.v[y];
-            }
-            0x8003..=0x8FF3 => {
-                // 8xy3 - XOR Vx, Vy
-                // Set Vx = Vx XOR Vy.
-                //
-                // Performs a bitwise exclusive OR on the values of Vx and Vy, then stores the result in Vx.
-                // An exclusive OR compares the corrseponding bits from two values, and if the bits are not both the same,
-                // then the corresponding bit in the result is set to 1. Otherwise, it is 0.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                self.v[x] = self.v[x] ^ self.v[y];
-            }
-            0x8004..=0x8FF4 => {
-                // 8xy4 - ADD Vx, Vy
-                // Set Vx = Vx + Vy, set VF = carry.
-                //
-                // The values of Vx and Vy are added together.
-                // If the result is greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0.
-                // Only the lowest 8 bits of the result are kept, and stored in Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                let result = self.v[x] as u16 + self.v[y] as u16;
-                self.v[0xF as usize] = if result > 255 { 1 } else { 0 };
-                self.v[x] = self.v[x].wrapping_add(self.v[y]);
-            }
-            0x8005..=0x8FF5 => {
-                // 8xy5 - SUB Vx, Vy
-                // Set Vx = Vx - Vy, set VF = NOT borrow.
-                //
-                // If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                let xx = self.v[x];
-                let yy = self.v[y];
-
-                self.v[0xF as usize] = if xx > yy { 1 } else { 0 };
-                self.v[x] = xx.wrapping_sub(yy);
-            }
-            0x8006..=0x8FF6 => {
-                // 8xy6 - SHR Vx {, Vy}
-                // Set Vx = Vx SHR 1.
-                //
-                // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.v[0xF as usize] = if self.v[x] & 0x01 > 0 { 1 } else { 0 };
-                self.v[x] = self.v[x] >> 1;
-            }
-            0x8007..=0x8FF7 => {
-                // 8xy7 - SUBN Vx, Vy
-                // Set Vx = Vy - Vx, set VF = NOT borrow.
-                //
-                // If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-                let xx = self.v[x];
-                let yy = self.v[y];
-
-                self.v[0xF as usize] = if yy > xx { 1 } else { 0 };
-                self.v[x] = yy.wrapping_sub(xx);
-            }
-            0x800E..=0x8FFE => {
-                // 8xyE - SHL Vx {, Vy}
-                // Set Vx = Vx SHL 1.
-                //
-                // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
+            0x8 => match n {
+                0x0 => {
+                    // 8xy0 - LD Vx, Vy
+                    // Set Vx = Vy.
+                    // Stores the value of register Vy in register Vx.
+                    self.v[x] = self.v[y];
+                }
+                0x1 => {
+                    // 8xy1 - OR Vx, Vy
+                    // Set Vx = Vx OR Vy.
+                    //
+                    // Performs a bitwise OR on the values of Vx and Vy, then stores the result in Vx.
+                    // A bitwise OR compares the corresponding bits from two values, and if either bit is 1,
+                    // then the same bit in the result is also 1. Otherwise, it is 0.
+                    // With the `logic_resets_vf` quirk VF is reset to 0 afterwards.
+                    self.v[x] |= self.v[y];
+                    if self.quirks.logic_resets_vf {
+                        self.v[0xF] = 0;
+                    }
+                }
+                0x2 => {
+                    // 8xy2 - AND Vx, Vy
+                    // Set Vx = Vx AND Vy.
+                    //
+                    // Performs a bitwise AND on the values of Vx and Vy, then stores the result in Vx.
+                    // A bitwise AND compares the corrseponding bits from two values, and if both bits are 1,
+                    // then the same bit in the result is also 1. Otherwise, it is 0.
+                    // With the `logic_resets_vf` quirk VF is reset to 0 afterwards.
+                    self.v[x] &= self.v[y];
+                    if self.quirks.logic_resets_vf {
+                        self.v[0xF] = 0;
+                    }
+                }
+                0x3 => {
+                    // 8xy3 - XOR Vx, Vy
+                    // Set Vx = Vx XOR Vy.
+                    //
+                    // Performs a bitwise exclusive OR on the values of Vx and Vy, then stores the result in Vx.
+                    // An exclusive OR compares the corrseponding bits from two values, and if the bits are not both the same,
+                    // then the corresponding bit in the result is set to 1. Otherwise, it is 0.
+                    // With the `logic_resets_vf` quirk VF is reset to 0 afterwards.
+                    self.v[x] ^= self.v[y];
+                    if self.quirks.logic_resets_vf {
+                        self.v[0xF] = 0;
+                    }
+                }
+                0x4 => {
+                    // 8xy4 - ADD Vx, Vy
+                    // Set Vx = Vx + Vy, set VF = carry.
+                    //
+                    // The values of Vx and Vy are added together.
+                    // If the result is greater than 8 bits (i.e., > 255,) VF is set to 1, otherwise 0.
+                    // Only the lowest 8 bits of the result are kept, and stored in Vx.
+                    // The flag is written last, so it wins when x is F.
+                    let result = self.v[x] as u16 + self.v[y] as u16;
+                    self.v[x] = result as u8;
+                    self.v[0xF] = if result > 255 { 1 } else { 0 };
+                }
+                0x5 => {
+                    // 8xy5 - SUB Vx, Vy
+                    // Set Vx = Vx - Vy, set VF = NOT borrow.
+                    //
+                    // If Vx > Vy, then VF is set to 1, otherwise 0. Then Vy is subtracted from Vx, and the results stored in Vx.
+                    let xx = self.v[x];
+                    let yy = self.v[y];
 
-                self.v[0xF as usize] = if self.v[x] & 0x80 > 0 { 1 } else { 0 };
-                self.v[x] <<= 1;
-            }
-            0x9000..=0x9FF0 => {
+                    self.v[x] = xx.wrapping_sub(yy);
+                    self.v[0xF] = if xx > yy { 1 } else { 0 };
+                }
+                0x6 => {
+                    // 8xy6 - SHR Vx {, Vy}
+                    // Set Vx = Vx SHR 1.
+                    //
+                    // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
+                    // With the `shift_uses_vy` quirk Vy is shifted instead, and the result is stored in Vx.
+                    let source = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+                    self.v[x] = source >> 1;
+                    self.v[0xF] = if source & 0x01 > 0 { 1 } else { 0 };
+                }
+                0x7 => {
+                    // 8xy7 - SUBN Vx, Vy
+                    // Set Vx = Vy - Vx, set VF = NOT borrow.
+                    //
+                    // If Vy > Vx, then VF is set to 1, otherwise 0. Then Vx is subtracted from Vy, and the results stored in Vx.
+                    let xx = self.v[x];
+                    let yy = self.v[y];
+
+                    self.v[x] = yy.wrapping_sub(xx);
+                    self.v[0xF] = if yy > xx { 1 } else { 0 };
+                }
+                0xE => {
+                    // 8xyE - SHL Vx {, Vy}
+                    // Set Vx = Vx SHL 1.
+                    //
+                    // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
+                    // With the `shift_uses_vy` quirk Vy is shifted instead, and the result is stored in Vx.
+                    let source = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+                    self.v[x] = source << 1;
+                    self.v[0xF] = if source & 0x80 > 0 { 1 } else { 0 };
+                }
+                _ => return Err(CpuError::UnknownOpcode(opcode)),
+            },
+            0x9 if n == 0 => {
                 // 9xy0 - SNE Vx, Vy
                 // Skip next instruction if Vx != Vy.
                 //
                 // The values of Vx and Vy are compared, and if they are not equal, the program counter is increased by 2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let y = ((opcode & 0x00F0) >> 4) as usize;
-
                 if self.v[x] != self.v[y] {
-                    self.pc += 2;
+                    self.skip(ram);
                 }
             }
-            0xA000..=0xAFFF => {
+            0xA => {
                 // Annn - LD I, addr
                 // Set I = nnn.
                 //
                 // The value of register I is set to nnn.
-                self.i = opcode & 0x0FFF;
+                self.i = nnn;
             }
-            0xB000..=0xBFFF => {
+            0xB => {
                 // Bnnn - JP V0, addr
                 // Jump to location nnn + V0.
                 //
                 // The program counter is set to nnn plus the value of V0.
-                let delta = opcode & 0x0FFF;
-                self.pc = (self.v[0] as u16).wrapping_add(delta);
+                // With the `jump_uses_vx` quirk this is Bxnn, which jumps to xnn plus the value of Vx.
+                let offset = if self.quirks.jump_uses_vx { self.v[x] } else { self.v[0] };
+                self.pc = (offset as u16).wrapping_add(nnn);
             }
-            0xC000..=0xCFFF => {
+            0xC => {
                 // Cxkk - RND Vx, byte
                 // Set Vx = random byte AND kk.
                 //
                 // The interpreter generates a random number from 0 to 255, which is then ANDed with the value kk.
                 // The results are stored in Vx. See instruction 8xy2 for more information on AND.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let kk = (opcode & 0x00FF) as u8;
-                //let random: u8 = rand::thread_rng().gen_range(0, 255);
-                // TODO
-                let random: u8 = 0x4C;
-                self.v[x] = kk & random;
+                self.v[x] = kk & self.random_byte();
             }
-            0xD000..=0xDFFF => {
+            0xD => {
                 // Dxyn - DRW Vx, Vy, nibble
                 // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
                 //
@@ -311,125 +743,929 @@ impl Cpu {
                 // VF is set to 1, otherwise it is set to 0. If the sprite is positioned so part of
                 // it is outside the coordinates of the display, it wraps around to the opposite side of the screen.
                 // See instruction 8xy3 for more information on XOR, and section 2.4, Display, for more information on the Chip-8 screen and sprites.
-                let xi = ((opcode & 0x0F00) >> 8) as usize;
-                let yi = ((opcode & 0x00F0) >> 4) as usize;
-                let x = self.v[xi] as usize;
-                let y = self.v[yi] as usize;
-
-                let n = (opcode & 0x000F) as u16;
-                let from = self.i as usize;
-                let to = (self.i + n) as usize;
-                let collision = display.draw(x, y, &ram.memory[from..to]);
-                self.v[0xF] = if collision { 1 } else { 0 };
-                //println!("{:?}", &ram.memory[from..to]);
-            }
-            0xE09E..=0xEF9E => {
-                // Ex9E - SKP Vx
-                // Skip next instruction if key with the value of Vx is pressed.
-                //
-                // Checks the keyboard, and if the key corresponding to the value of Vx is currently in the down position, PC is increased by 2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                if keyboard.is_pressed(self.v[x]) {
-                    self.pc += 2;
-                }
-            }
-            0xE0A1..=0xEFA1 => {
-                // ExA1 - SKNP Vx
-                // Skip next instruction if key with the value of Vx is not pressed.
                 //
-                // Checks the keyboard, and if the key corresponding to the value of Vx is currently in the up position, PC is increased by 2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                if keyboard.is_released(self.v[x]) {
-                    self.pc += 2;
-                }
-            }
-            0xF007..=0xFF07 => {
-                // Fx07 - LD Vx, DT
-                // Set Vx = delay timer value.
-                //
-                // The value of DT is placed into Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.v[x] = self.dt;
-            }
-            0xF00A..=0xFF0A => {
-                // Fx0A - LD Vx, K
-                // Wait for a key press, store the value of the key in Vx.
+                // Dxy0 - DRW Vx, Vy, 0 (SUPER-CHIP)
+                // In high resolution mode a sprite with a height of 0 is a 16x16 sprite of 32 bytes.
                 //
-                // All execution stops until a key is pressed, then the value of that key is stored in Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let key_pressed = keyboard.wait_key();
-                self.v[x] = key_pressed;
-            }
-            0xF015..=0xFF15 => {
-                // Fx15 - LD DT, Vx
-                // Set delay timer = Vx.
+                // In high resolution mode SUPER-CHIP sets VF to the number of rows which collided,
+                // plus the number of rows clipped at the bottom of the screen.
                 //
-                // DT is set equal to the value of Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.dt = self.v[x];
-            }
-            0xF018..=0xFF18 => {
-                // Fx18 - LD ST, Vx
-                // Set sound timer = Vx.
+                // XO-CHIP draws the sprite to each selected plane, the sprite holds the data of the planes one after the other.
                 //
-                // ST is set equal to the value of Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.st = self.v[x];
-            }
-            0xF01E..=0xFF1E => {
-                // Fx1E - ADD I, Vx
-                // Set I = I + Vx.
-                //
-                // The values of I and Vx are added, and the results are stored in I.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.i += self.v[x] as u16;
+                // With the `display_wait` quirk only one sprite is drawn per frame, a second sprite
+                // is retried on the next cycles until the frame ends.
+                if self.quirks.display_wait {
+                    if self.drawn_this_frame {
+                        self.pc = self.pc.wrapping_sub(2);
+                        return Ok(());
+                    }
+                    self.drawn_this_frame = true;
+                }
+
+                let large = n == 0 && display.is_hires();
+                let from = self.i as usize;
+                let to = from + if large { 32 } else { n as usize } * display.selected_plane_count();
+                if to > ram.size() {
+                    return Err(CpuError::MemoryOutOfBounds(self.i));
+                }
+
+                let collision = if large {
+                    display.draw_large(self.v[x] as usize, self.v[y] as usize, &ram.bytes()[from..to])
+                } else {
+                    display.draw(self.v[x] as usize, self.v[y] as usize, &ram.bytes()[from..to])
+                };
+                self.v[0xF] = if display.is_hires() {
+                    collision.rows + collision.clipped_rows
+                } else if collision.any() {
+                    1
+                } else {
+                    0
+                };
             }
-            0xF029..=0xFF29 => {
-                // Fx29 - LD F, Vx
-                // Set I = location of sprite for digit Vx.
-                //
-                // The value of I is set to the location for the hexadecimal sprite corresponding to the value of Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                self.i = self.v[x] as u16 * 5;
+            0xE => match kk {
+                0x9E => {
+                    // Ex9E - SKP Vx
+                    // Skip next instruction if key with the value of Vx is pressed.
+                    //
+                    // Checks the keyboard, and if the key corresponding to the value of Vx is currently in the down position, PC is increased by 2.
+                    if input.is_down(self.v[x]) {
+                        self.skip(ram);
+                    }
+                }
+                0xA1 => {
+                    // ExA1 - SKNP Vx
+                    // Skip next instruction if key with the value of Vx is not pressed.
+                    //
+                    // Checks the keyboard, and if the key corresponding to the value of Vx is currently in the up position, PC is increased by 2.
+                    if !input.is_down(self.v[x]) {
+                        self.skip(ram);
+                    }
+                }
+                _ => return Err(CpuError::UnknownOpcode(opcode)),
+            },
+            0xF => match kk {
+                0x01 => {
+                    // Fn01 - PLANE n (XO-CHIP)
+                    // Select the drawing planes by the bitmask n.
+                    //
+                    // Drawing, clearing and scrolling only affect the selected planes, bit 0 is the first plane.
+                    display.select_planes(x as u8);
+                }
+                0x00 if x == 0 => {
+                    // F000 nnnn - LD I, long addr (XO-CHIP)
+                    // Set I = nnnn.
+                    //
+                    // The 16 bit address is stored in the word following the instruction, which is skipped.
+                    self.i = ram.read_u16(self.pc).ok_or(CpuError::MemoryOutOfBounds(self.pc))?;
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                0x07 => {
+                    // Fx07 - LD Vx, DT
+                    // Set Vx = delay timer value.
+                    //
+                    // The value of DT is placed into Vx.
+                    self.v[x] = self.dt;
+                }
+                0x0A => {
+                    // Fx0A - LD Vx, K
+                    // Wait for a key press, store the value of the key in Vx.
+                    //
+                    // All execution stops until a key is pressed, then the value of that key is stored in Vx.
+                    // Instead of blocking, the instruction is executed again on the next cycle until a key is down.
+                    //
+                    // With the `wait_key_on_release` quirk the key has to be pressed and released while waiting, like on
+                    // the COSMAC VIP. A key which is held down from before doesn't count, so a single press isn't read twice.
+                    if !self.waiting_key {
+                        input.begin_wait();
+                        self.waiting_key = true;
+                    }
+                    match input.wait(self.quirks.wait_key_on_release) {
+                        Some(key) => {
+                            self.v[x] = key;
+                            self.waiting_key = false;
+                        }
+                        None => self.pc = self.pc.wrapping_sub(2),
+                    }
+                }
+                0x15 => {
+                    // Fx15 - LD DT, Vx
+                    // Set delay timer = Vx.
+                    //
+                    // DT is set equal to the value of Vx.
+                    self.dt = self.v[x];
+                }
+                0x18 => {
+                    // Fx18 - LD ST, Vx
+                    // Set sound timer = Vx.
+                    //
+                    // ST is set equal to the value of Vx.
+                    self.st = self.v[x];
+                }
+                0x1E => {
+                    // Fx1E - ADD I, Vx
+                    // Set I = I + Vx.
+                    //
+                    // The values of I and Vx are added, and the results are stored in I.
+                    // I is kept in the address range of the RAM, 12 bits or 16 bits in extended mode, the size is a power of two.
+                    let mask = ram.size() as u32 - 1;
+                    let result = self.i as u32 + self.v[x] as u32;
+                    if self.quirks.fx1e_sets_vf {
+                        self.v[0xF] = if result > mask { 1 } else { 0 };
+                    }
+                    self.i = (result & mask) as u16;
+                }
+                0x29 => {
+                    // Fx29 - LD F, Vx
+                    // Set I = location of sprite for digit Vx.
+                    //
+                    // The value of I is set to the location for the hexadecimal sprite corresponding to the value of Vx.
+                    // The font is loaded at the font base, the very beginning of the memory by default, each glyph is 5 bytes long.
+                    // Only the lowest nibble is used, so an invalid digit can't point past the font.
+                    self.i = self.font_base + (self.v[x] & 0x0F) as u16 * 5;
+                }
+                0x30 => {
+                    // Fx30 - LD HF, Vx (SUPER-CHIP)
+                    // Set I = location of the big sprite for digit Vx.
+                    //
+                    // The big font has 10 byte long glyphs for the digits 0 through 9 only,
+                    // so the digit is taken modulo 10 to keep I within the font.
+                    self.i = (BIG_FONT_BASE + (self.v[x] % 10) as usize * 10) as u16;
+                }
+                0x33 => {
+                    // Fx33 - LD B, Vx
+                    // Store BCD representation of Vx in memory locations I, I+1, and I+2.
+                    //
+                    // The interpreter takes the decimal value of Vx, and places the hundreds digit
+                    // in memory at location in I, the tens digit at location I+1, and the ones digit at location I+2.
+                    let i = self.i as usize;
+                    let num = self.v[x];
+
+                    if i + 2 >= ram.size() {
+                        return Err(CpuError::MemoryOutOfBounds(self.i));
+                    }
+                    ram.write(i, num / 100);
+                    ram.write(i + 1, (num / 10) % 10);
+                    ram.write(i + 2, num % 10);
+                }
+                0x3A => {
+                    // Fx3A - PITCH Vx (XO-CHIP)
+                    // Set the pitch of the beep to Vx.
+                    //
+                    // The beep plays at base frequency * 2^((Vx - 64) / 48), so 64 is the base frequency.
+                    self.pitch = self.v[x];
+                }
+                0x55 => {
+                    // Fx55 - LD [I], Vx
+                    // Store registers V0 through Vx in memory starting at location I.
+                    //
+                    // The interpreter copies the values of registers V0 through Vx into memory, starting at the address in I.
+                    if self.i as usize + x >= ram.size() {
+                        return Err(CpuError::MemoryOutOfBounds(self.i));
+                    }
+                    for i in 0..=x {
+                        ram.write(self.i as usize + i, self.v[i]);
+                    }
+                    if self.quirks.load_store_increments_i {
+                        self.i = self.i.wrapping_add(x as u16 + 1);
+                    }
+                }
+                0x65 => {
+                    // Fx65 - LD Vx, [I]
+                    // Read registers V0 through Vx from memory starting at location I.
+                    //
+                    // The interpreter reads values from memory starting at location I into registers V0 through Vx.
+                    if self.i as usize + x >= ram.size() {
+                        return Err(CpuError::MemoryOutOfBounds(self.i));
+                    }
+                    for i in 0..=x {
+                        self.v[i] = ram.read(self.i as usize + i);
+                    }
+                    if self.quirks.load_store_increments_i {
+                        self.i = self.i.wrapping_add(x as u16 + 1);
+                    }
+                }
+                _ => return Err(CpuError::UnknownOpcode(opcode)),
+            },
+            _ => return Err(CpuError::UnknownOpcode(opcode)),
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
+    }
+}
+
+/// Prints the registers and the stack, e.g.
+///
+/// ```text
+/// V0-V7: 00 1F 00 00 00 00 00 00
+/// V8-VF: 00 00 00 00 00 00 00 01
+/// I: 0x0222  PC: 0x0204  SP: 1  DT: 00  ST: 00
+/// Stack: 0x020A
+/// ```
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (half, registers) in self.v.chunks(8).enumerate() {
+            write!(f, "V{:X}-V{:X}:", half * 8, half * 8 + 7)?;
+            for value in registers {
+                write!(f, " {:02X}", value)?;
             }
-            0xF033..=0xFF33 => {
-                // Fx33 - LD B, Vx
-                // Store BCD representation of Vx in memory locations I, I+1, and I+2.
-                //
-                // The interpreter takes the decimal value of Vx, and places the hundreds digit
-                // in memory at location in I, the tens digit at location I+1, and the ones digit at location I+2.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                let i = self.i as usize;
-                let num = self.v[x];
-
-                ram.memory[i] = num / 100;
-                ram.memory[i + 1] = (num / 10) % 10;
-                ram.memory[i + 2] = (num % 100) % 10;
+            writeln!(f)?;
+        }
+        writeln!(f, "I: {:#06X}  PC: {:#06X}  SP: {}  DT: {:02X}  ST: {:02X}", self.i, self.pc, self.sp, self.dt, self.st)?;
+        write!(f, "Stack:")?;
+        for address in self.stack.iter().take(self.sp as usize) {
+            write!(f, " {:#06X}", address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use core::str;
+    use crate::color::Color;
+    use crate::input::ScriptedInput;
+    use crate::keyboard::Keyboard;
+    use crate::renderer::NullRenderer;
+
+    /// A CPU with its memory and display, running a program loaded at 0x200
+    struct Machine {
+        cpu: Cpu,
+        ram: Ram,
+        display: Display<NullRenderer>,
+    }
+
+    impl Machine {
+        fn new(program: &[u8]) -> Machine {
+            let mut ram = Ram::new();
+            ram.load_program(program, 0x200).unwrap();
+            Machine {
+                cpu: Cpu::new(),
+                ram,
+                display: Display::with_renderer(Color::White, NullRenderer),
             }
-            0xF055..=0xFF55 => {
-                // Fx55 - LD [I], Vx
-                // Store registers V0 through Vx in memory starting at location I.
-                //
-                // The interpreter copies the values of registers V0 through Vx into memory, starting at the address in I.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                for i in 0..x {
-                    ram.memory[self.i as usize + i] = self.v[i];
-                }
+        }
+
+        /// Executes one instruction without any key down
+        fn step(&mut self) -> Result<StepResult, CpuError> {
+            self.step_with(&mut ScriptedInput::new(&[]))
+        }
+
+        /// Executes one instruction reading the keys from the input
+        fn step_with(&mut self, input: &mut ScriptedInput) -> Result<StepResult, CpuError> {
+            self.cpu.step(&mut self.ram, input, &mut self.display)
+        }
+
+        /// Executes `n` instructions, all of them must succeed
+        fn run(&mut self, n: usize) {
+            for _ in 0..n {
+                self.step().unwrap();
             }
-            0xF065..=0xFF65 => {
-                // Fx65 - LD Vx, [I]
-                // Read registers V0 through Vx from memory starting at location I.
-                //
-                // The interpreter reads values from memory starting at location I into registers V0 through Vx.
-                let x = ((opcode & 0x0F00) >> 8) as usize;
-                for i in 0..x {
-                    self.v[i] = ram.memory[self.i as usize + i];
-                }
+        }
+    }
+
+    /// Collects formatted text without allocating
+    struct Buffer {
+        bytes: [u8; 512],
+        len: usize,
+    }
+
+    impl Buffer {
+        fn new() -> Buffer {
+            Buffer { bytes: [0; 512], len: 0 }
+        }
+
+        fn as_str(&self) -> &str {
+            str::from_utf8(&self.bytes[..self.len]).unwrap()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decodes_opcodes_by_nibbles() {
+        // V0, V1, opcode, expected V0, expected VF
+        let cases: [(u8, u8, u16, u8, u8); 14] = [
+            (5, 3, 0x8010, 3, 0),
+            (0b1100, 0b1010, 0x8011, 0b1110, 0),
+            (0b1100, 0b1010, 0x8012, 0b1000, 0),
+            (0b1100, 0b1010, 0x8013, 0b0110, 0),
+            (200, 100, 0x8014, 44, 1),
+            (20, 10, 0x8014, 30, 0),
+            (5, 3, 0x8015, 2, 1),
+            (3, 5, 0x8015, 254, 0),
+            (5, 0, 0x8016, 2, 1),
+            (3, 5, 0x8017, 2, 1),
+            (5, 3, 0x8017, 254, 0),
+            (0x81, 0, 0x801E, 0x02, 1),
+            (7, 0, 0x6042, 0x42, 0),
+            (250, 0, 0x700A, 4, 0),
+        ];
+
+        for &(v0, v1, opcode, expected, vf) in cases.iter() {
+            let mut machine = Machine::new(&opcode.to_be_bytes());
+            machine.cpu.v[0] = v0;
+            machine.cpu.v[1] = v1;
+            machine.step().unwrap();
+            assert_eq!(machine.cpu.v[0], expected, "V0 after {:04X}", opcode);
+            assert_eq!(machine.cpu.v[0xF], vf, "VF after {:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn logic_resets_vf_depending_on_the_quirk() {
+        for &opcode in [0x8011u16, 0x8012, 0x8013].iter() {
+            for &(logic_resets_vf, vf) in [(false, 7), (true, 0)].iter() {
+                let mut machine = Machine::new(&opcode.to_be_bytes());
+                machine.cpu.set_quirks(Quirks { logic_resets_vf, ..Quirks::new() });
+                machine.cpu.v[0] = 0b1100;
+                machine.cpu.v[1] = 0b1010;
+                machine.cpu.v[0xF] = 7;
+                machine.run(1);
+                assert_eq!(machine.cpu.v[0xF], vf, "VF after {:04X}, logic_resets_vf: {}", opcode, logic_resets_vf);
             }
+        }
+    }
+
+    #[test]
+    fn jumps_and_skips() {
+        // JP 0x208, SE V0 0, skipped, SNE V0 0, LD I 0x123
+        let mut machine = Machine::new(&[0x12, 0x08, 0, 0, 0, 0, 0, 0, 0x30, 0x00, 0xFF, 0xFF, 0x40, 0x00, 0xA1, 0x23]);
+        machine.run(4);
+        assert_eq!(machine.cpu.i, 0x123);
+        assert_eq!(machine.cpu.pc, 0x210);
+
+        // JP V0, 0x300
+        let mut machine = Machine::new(&[0xB3, 0x00]);
+        machine.cpu.v[0] = 0x10;
+        machine.run(1);
+        assert_eq!(machine.cpu.pc, 0x310);
+    }
 
-            _ => {
-                //panic!("Unknown opcode: {:x}", opcode);
+    #[test]
+    fn overlapping_sprites_set_vf() {
+        // LD V0 10, LD V1 5, LD I 0x210, DRW V0 V1 1, LD V0 11, DRW V0 V1 1, LD V0 30, DRW V0 V1 1, sprite
+        let mut machine = Machine::new(&[
+            0x60, 0x0A, 0x61, 0x05, 0xA2, 0x10, 0xD0, 0x11, 0x60, 0x0B, 0xD0, 0x11, 0x60, 0x1E, 0xD0, 0x11, 0xC0,
+        ]);
+        machine.run(4);
+        assert_eq!(machine.cpu.v[0xF], 0);
+        assert!(machine.display.pixel(10, 5) && machine.display.pixel(11, 5));
+        assert!(!machine.display.pixel(0, 1));
+
+        // The second sprite overlaps the first one at (11, 5)
+        machine.run(2);
+        assert_eq!(machine.cpu.v[0xF], 1);
+        assert!(machine.display.pixel(10, 5) && !machine.display.pixel(11, 5) && machine.display.pixel(12, 5));
+
+        machine.run(2);
+        assert_eq!(machine.cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn sprites_straddling_the_bottom_edge_are_clipped() {
+        // LD V0 8, LD V1 30, LD I 0x20E, DRW V0 V1 4, DRW V0 V1 4, JP 0x20A, (unused), the sprite
+        let mut machine = Machine::new(&[
+            0x60, 0x08, 0x61, 0x1E, 0xA2, 0x0E, 0xD0, 0x14, 0xD0, 0x14, 0x12, 0x0A, 0x00, 0x00, 0x80, 0x80, 0x80, 0x80,
+        ]);
+        // The clipped rows would land on these pixels if they wrapped
+        machine.display.draw(8, 0, &[0x80, 0x80]);
+        machine.run(4);
+        assert!(machine.display.pixel(8, 30) && machine.display.pixel(8, 31));
+        assert!(machine.display.pixel(8, 0) && machine.display.pixel(8, 1));
+        assert_eq!(machine.cpu.v[0xF], 0);
+
+        // Only the visible rows collide
+        machine.run(1);
+        assert!(!machine.display.pixel(8, 30) && !machine.display.pixel(8, 31));
+        assert!(machine.display.pixel(8, 0) && machine.display.pixel(8, 1));
+        assert_eq!(machine.cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn display_wait_holds_the_second_sprite_until_the_frame_ends() {
+        // LD I 0x206, DRW V0 V0 1, DRW V0 V1 1, the sprite
+        let mut machine = Machine::new(&[0xA2, 0x06, 0xD0, 0x01, 0xD0, 0x11, 0x80]);
+        machine.cpu.set_quirks(Quirks { display_wait: true, ..Quirks::new() });
+        machine.cpu.v[1] = 1;
+        machine.run(2);
+        assert!(machine.display.pixel(0, 0));
+
+        // The timer stands in for the 60Hz frame boundary
+        machine.run(3);
+        assert_eq!(machine.cpu.pc, 0x204);
+        assert!(!machine.display.pixel(0, 1));
+
+        machine.cpu.tick_timers();
+        machine.run(1);
+        assert_eq!(machine.cpu.pc, 0x206);
+        assert!(machine.display.pixel(0, 1));
+    }
+
+    #[test]
+    fn replays_scripted_key_presses() {
+        // LD V0 5, SKP V0, LD V1 1, LD V2 K, SKP V0, LD V3 1, LD V4 K, LD V5 K
+        let mut machine = Machine::new(&[
+            0x60, 0x05, 0xE0, 0x9E, 0x61, 0x01, 0xF2, 0x0A, 0xE0, 0x9E, 0x63, 0x01, 0xF4, 0x0A, 0xF5, 0x0A,
+        ]);
+        let mut input = ScriptedInput::new(&[5, 7]);
+
+        // Key 5 is down: SKP skips, then the wait consumes it and key 7 is pressed
+        for _ in 0..3 {
+            machine.step_with(&mut input).unwrap();
+        }
+        assert_eq!((machine.cpu.v[1], machine.cpu.v[2]), (0, 5));
+        assert_eq!(input.current(), Some(7));
+
+        for _ in 0..3 {
+            machine.step_with(&mut input).unwrap();
+        }
+        assert_eq!((machine.cpu.v[3], machine.cpu.v[4]), (1, 7));
+        assert!(input.is_finished());
+
+        // No key is left, the last wait never ends
+        for _ in 0..3 {
+            machine.step_with(&mut input).unwrap();
+        }
+        assert_eq!(machine.cpu.pc, 0x20E);
+        assert!(machine.cpu.is_waiting_key());
+    }
+
+    #[test]
+    fn waiting_for_a_key_ends_on_press_or_on_release() {
+        for &wait_key_on_release in [false, true].iter() {
+            // LD V2 K
+            let mut machine = Machine::new(&[0xF2, 0x0A]);
+            machine.cpu.set_quirks(Quirks { wait_key_on_release, ..Quirks::new() });
+            let mut keyboard = Keyboard::new();
+            machine.cpu.step(&mut machine.ram, &mut keyboard, &mut machine.display).unwrap();
+            assert!(machine.cpu.is_waiting_key());
+
+            keyboard.press(0x7);
+            machine.cpu.step(&mut machine.ram, &mut keyboard, &mut machine.display).unwrap();
+            if wait_key_on_release {
+                assert!(machine.cpu.is_waiting_key());
+                assert_eq!(machine.cpu.pc, 0x200);
+
+                keyboard.release(0x7);
+                machine.cpu.step(&mut machine.ram, &mut keyboard, &mut machine.display).unwrap();
             }
+            assert!(!machine.cpu.is_waiting_key(), "wait_key_on_release: {}", wait_key_on_release);
+            assert_eq!((machine.cpu.v[2], machine.cpu.pc), (0x7, 0x202));
+        }
+    }
+
+    #[test]
+    fn nested_calls_return_in_order() {
+        // CALL 0x206, JP 0x204, (unused), CALL 0x20A, RET, RET
+        let mut machine = Machine::new(&[0x22, 0x06, 0x12, 0x04, 0x00, 0x00, 0x22, 0x0A, 0x00, 0xEE, 0x00, 0xEE]);
+        machine.run(2);
+        assert_eq!(machine.cpu.sp, 2);
+        assert_eq!(machine.cpu.stack[..2], [0x202, 0x208]);
+        assert_eq!(machine.cpu.pc, 0x20A);
+
+        machine.run(1);
+        assert_eq!((machine.cpu.sp, machine.cpu.pc), (1, 0x208));
+        machine.run(1);
+        assert_eq!((machine.cpu.sp, machine.cpu.pc), (0, 0x202));
+    }
+
+    #[test]
+    fn return_with_an_empty_stack_fails() {
+        let mut machine = Machine::new(&[0x00, 0xEE]);
+        assert_eq!(machine.step(), Err(CpuError::StackUnderflow));
+        assert_eq!(machine.cpu.sp, 0);
+    }
+
+    #[test]
+    fn seventeenth_nested_call_overflows() {
+        // CALL 0x202, CALL 0x204, ... each call goes one instruction deeper
+        let mut program = [0; 34];
+        for (index, call) in program.chunks_mut(2).enumerate() {
+            call.copy_from_slice(&(0x2202 + index as u16 * 2).to_be_bytes());
+        }
+        let mut machine = Machine::new(&program);
+        machine.run(16);
+        assert_eq!(machine.cpu.sp, 16);
+        assert_eq!(machine.step(), Err(CpuError::StackOverflow));
+        assert_eq!(machine.cpu.sp, 16);
+    }
+
+    #[test]
+    fn points_i_at_the_font_glyph() {
+        // LD F V3
+        for &digit in [0x0Au8, 0x1A].iter() {
+            let mut machine = Machine::new(&[0xF3, 0x29]);
+            machine.cpu.v[3] = digit;
+            machine.run(1);
+            assert_eq!(machine.cpu.i, 50, "digit {:02X}", digit);
+        }
+    }
+
+    #[test]
+    fn stores_the_decimal_digits() {
+        for &(value, digits) in [(0u8, [0, 0, 0]), (9, [0, 0, 9]), (99, [0, 9, 9]), (255, [2, 5, 5])].iter() {
+            // LD B V0
+            let mut machine = Machine::new(&[0xF0, 0x33]);
+            machine.cpu.v[0] = value;
+            machine.cpu.i = 0x300;
+            machine.run(1);
+            assert_eq!(machine.ram.bytes()[0x300..0x303], digits, "value {}", value);
+        }
+
+        // The third digit would go past the end of the memory
+        let mut machine = Machine::new(&[0xF0, 0x33]);
+        machine.cpu.i = 0xFFE;
+        assert_eq!(machine.step(), Err(CpuError::MemoryOutOfBounds(0xFFE)));
+        assert_eq!(machine.ram.bytes()[0xFFE..], [0, 0]);
+    }
+
+    #[test]
+    fn stores_and_loads_registers_up_to_vx() {
+        // LD [I] V5, LD V5 [I]
+        let mut machine = Machine::new(&[0xF5, 0x55, 0xF5, 0x65]);
+        let registers = [1, 2, 3, 4, 5, 6];
+        machine.cpu.v[..6].copy_from_slice(&registers);
+        machine.cpu.i = 0x300;
+        machine.run(1);
+        assert_eq!(machine.ram.bytes()[0x300..0x307], [1, 2, 3, 4, 5, 6, 0]);
+
+        machine.cpu.v = [0; 16];
+        machine.run(1);
+        assert_eq!(machine.cpu.v[..6], registers);
+        assert_eq!(machine.cpu.v[6], 0);
+
+        // V0 through V5 would go past the end of the memory
+        let mut machine = Machine::new(&[0xF5, 0x55]);
+        machine.cpu.i = (machine.ram.size() - 5) as u16;
+        assert_eq!(machine.step(), Err(CpuError::MemoryOutOfBounds(machine.cpu.i)));
+    }
+
+    #[test]
+    fn add_to_i_wraps_around_a_reduced_memory() {
+        // ADD I V0
+        let mut machine = Machine::new(&[0xF0, 0x1E]);
+        machine.ram.set_size(0x400);
+        machine.cpu.i = 0x3FF;
+        machine.cpu.v[0] = 2;
+        machine.run(1);
+        assert_eq!(machine.cpu.i, 0x001);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn long_load_reaches_the_extended_memory() {
+        // LD I 0x1234 (long), LD V1 [I]
+        let mut machine = Machine::new(&[0xF0, 0x00, 0x12, 0x34, 0xF1, 0x65]);
+        machine.ram.set_extended(true);
+        machine.ram.write_u16(0x1234, 0xABCD);
+        machine.run(2);
+        assert_eq!(machine.cpu.v[..2], [0xAB, 0xCD]);
+        assert_eq!(machine.cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn fetching_past_the_end_of_the_memory_fails() {
+        let mut machine = Machine::new(&[]);
+        assert_eq!(machine.ram.read_u16(4094), Some(0));
+        assert_eq!(machine.ram.read_u16(4095), None);
+
+        machine.cpu.pc = 4095;
+        assert_eq!(machine.step(), Err(CpuError::MemoryOutOfBounds(4095)));
+        assert_eq!(machine.cpu.pc, 4095);
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for &opcode in [0x5001u16, 0x8008, 0x9001, 0xE000, 0xF0FF].iter() {
+            let mut machine = Machine::new(&opcode.to_be_bytes());
+            assert_eq!(machine.step(), Err(CpuError::UnknownOpcode(opcode)));
+        }
+    }
+
+    #[test]
+    fn lenient_mode_logs_and_skips_unknown_opcodes() {
+        // (unknown), LD V0 1, (unknown), (unknown), LD V1 2
+        let mut machine = Machine::new(&[0x50, 0x01, 0x60, 0x01, 0x80, 0x08, 0xF0, 0xFF, 0x61, 0x02]);
+        machine.cpu.set_strict_opcodes(false);
+        machine.run(5);
+        assert_eq!(machine.cpu.unknown_opcodes(), [(0x200, 0x5001), (0x204, 0x8008), (0x206, 0xF0FF)]);
+        assert_eq!(machine.cpu.v[..2], [1, 2]);
+        assert_eq!(machine.cpu.pc, 0x20A);
+
+        // Only the last ones are kept
+        let program = [0xFF; (UNKNOWN_LOG_SIZE + 2) * 2];
+        let mut machine = Machine::new(&program);
+        machine.cpu.set_strict_opcodes(false);
+        machine.run(UNKNOWN_LOG_SIZE + 2);
+        let log = machine.cpu.unknown_opcodes();
+        assert_eq!(log.len(), UNKNOWN_LOG_SIZE);
+        assert_eq!((log[0].0, log[UNKNOWN_LOG_SIZE - 1].0), (0x204, 0x200 + (UNKNOWN_LOG_SIZE as u16 + 1) * 2));
+    }
+
+    #[test]
+    fn drawing_costs_more_than_arithmetic() {
+        for &(opcode, cost) in [(0x6001u16, 1), (0x8014, 1), (0xA200, 1), (0x2300, 2), (0xD015, 4), (0x00E0, 4), (0x00C4, 4), (0x00EE, 1)].iter() {
+            assert_eq!(opcode_cost(opcode), cost, "cost of {:04X}", opcode);
         }
     }
+
+    #[test]
+    fn timers_count_down_to_zero_and_stop() {
+        let mut machine = Machine::new(&[]);
+        machine.cpu.dt = 5;
+        machine.cpu.st = 2;
+        for &expected in [4, 3, 2, 1, 0].iter() {
+            machine.cpu.tick_timers();
+            assert_eq!(machine.cpu.dt, expected);
+        }
+        assert_eq!(machine.cpu.st, 0);
+
+        machine.cpu.tick_timers();
+        assert_eq!((machine.cpu.dt, machine.cpu.st), (0, 0));
+    }
+
+    #[test]
+    fn switches_between_the_resolutions_and_clears_the_screen() {
+        // HIGH, LOW
+        let mut machine = Machine::new(&[0x00, 0xFF, 0x00, 0xFE]);
+        assert_eq!((machine.display.width(), machine.display.height()), (64, 32));
+        machine.display.draw(0, 0, &[0x80]);
+
+        machine.run(1);
+        assert!(machine.display.is_hires());
+        assert_eq!((machine.display.width(), machine.display.height()), (128, 64));
+        assert!(!machine.display.pixel(0, 0));
+        machine.display.draw(127, 63, &[0x80]);
+
+        machine.run(1);
+        assert!(!machine.display.is_hires());
+        assert_eq!((machine.display.width(), machine.display.height()), (64, 32));
+        assert!(!machine.display.pixel(63, 31));
+    }
+
+    #[test]
+    fn large_sprites_count_the_colliding_rows() {
+        // HIGH, LD I 0x20A, DRW V0 V0 0, LD V1 8, DRW V0 V1 0, a 16x16 block
+        let mut program = [0xFF; 42];
+        program[..10].copy_from_slice(&[0x00, 0xFF, 0xA2, 0x0A, 0xD0, 0x00, 0x61, 0x08, 0xD0, 0x10]);
+        let mut machine = Machine::new(&program);
+        machine.run(3);
+        assert_eq!(machine.cpu.v[0xF], 0);
+        assert!(machine.display.pixel(0, 0) && machine.display.pixel(15, 15) && !machine.display.pixel(16, 0));
+
+        // The second sprite overlaps the lower half of the first one
+        machine.run(2);
+        assert_eq!(machine.cpu.v[0xF], 8);
+        assert!(!machine.display.pixel(15, 15) && machine.display.pixel(15, 16));
+    }
+
+    #[test]
+    fn sys_is_ignored() {
+        // SYS 0x123
+        let mut machine = Machine::new(&[0x01, 0x23]);
+        machine.cpu.v[0] = 7;
+        machine.run(1);
+        assert_eq!(machine.cpu.pc, 0x202);
+        assert_eq!((machine.cpu.v[0], machine.cpu.i, machine.cpu.sp), (7, 0, 0));
+        assert!(!machine.cpu.is_halted());
+    }
+
+    #[test]
+    fn shifts_vx_or_vy_depending_on_the_quirk() {
+        // opcode, shift_uses_vy, expected V0, expected VF, with V0 = 0x81 and V1 = 0x42
+        let cases: [(u16, bool, u8, u8); 4] = [
+            (0x8016, false, 0x40, 1),
+            (0x8016, true, 0x21, 0),
+            (0x801E, false, 0x02, 1),
+            (0x801E, true, 0x84, 0),
+        ];
+
+        for &(opcode, shift_uses_vy, expected, vf) in cases.iter() {
+            let mut machine = Machine::new(&opcode.to_be_bytes());
+            machine.cpu.set_quirks(Quirks { shift_uses_vy, ..Quirks::new() });
+            machine.cpu.v[0] = 0x81;
+            machine.cpu.v[1] = 0x42;
+            machine.run(1);
+            assert_eq!(machine.cpu.v[0], expected, "V0 after {:04X}, shift_uses_vy: {}", opcode, shift_uses_vy);
+            assert_eq!(machine.cpu.v[0xF], vf, "VF after {:04X}, shift_uses_vy: {}", opcode, shift_uses_vy);
+            assert_eq!(machine.cpu.v[1], 0x42);
+        }
+    }
+
+    #[test]
+    fn load_and_store_increment_i_depending_on_the_quirk() {
+        for &load_store_increments_i in [false, true].iter() {
+            let expected_i = if load_store_increments_i { 0x305 } else { 0x300 };
+
+            // LD [I] V4
+            let mut machine = Machine::new(&[0xF4, 0x55]);
+            machine.cpu.set_quirks(Quirks { load_store_increments_i, ..Quirks::new() });
+            machine.cpu.v[..5].copy_from_slice(&[1, 2, 3, 4, 5]);
+            machine.cpu.i = 0x300;
+            machine.run(1);
+            assert_eq!(machine.ram.bytes()[0x300..0x306], [1, 2, 3, 4, 5, 0]);
+            assert_eq!(machine.cpu.i, expected_i, "I after store, load_store_increments_i: {}", load_store_increments_i);
+
+            // LD V4 [I]
+            let mut machine = Machine::new(&[0xF4, 0x65]);
+            machine.cpu.set_quirks(Quirks { load_store_increments_i, ..Quirks::new() });
+            machine.ram.write(0x300, 9);
+            machine.ram.write(0x304, 8);
+            machine.cpu.i = 0x300;
+            machine.run(1);
+            assert_eq!(machine.cpu.v[..6], [9, 0, 0, 0, 8, 0]);
+            assert_eq!(machine.cpu.i, expected_i, "I after load, load_store_increments_i: {}", load_store_increments_i);
+        }
+    }
+
+    #[test]
+    fn jump_with_offset_uses_v0_or_vx_depending_on_the_quirk() {
+        for &(jump_uses_vx, expected) in [(false, 0x311), (true, 0x322)].iter() {
+            // JP V0, 0x310
+            let mut machine = Machine::new(&[0xB3, 0x10]);
+            machine.cpu.set_quirks(Quirks { jump_uses_vx, ..Quirks::new() });
+            machine.cpu.v[0] = 0x01;
+            machine.cpu.v[3] = 0x12;
+            machine.run(1);
+            assert_eq!(machine.cpu.pc, expected, "jump_uses_vx: {}", jump_uses_vx);
+        }
+    }
+
+    #[test]
+    fn trace_keeps_the_last_opcodes() {
+        // LD V0 0, LD V0 1, LD V0 2, ...
+        let mut program = [0; (TRACE_SIZE + 6) * 2];
+        for (index, load) in program.chunks_mut(2).enumerate() {
+            load.copy_from_slice(&(0x6000 + index as u16).to_be_bytes());
+        }
+        let mut machine = Machine::new(&program);
+        machine.run(1);
+        machine.cpu.set_trace(true);
+        machine.run(3);
+        assert_eq!(machine.cpu.trace_log(), [0x6001, 0x6002, 0x6003]);
+
+        // The oldest opcodes are dropped once the log is full
+        machine.run(TRACE_SIZE + 2);
+        let log = machine.cpu.trace_log();
+        assert_eq!(log.len(), TRACE_SIZE);
+        assert_eq!((log[0], log[TRACE_SIZE - 1]), (0x6006, 0x6000 + TRACE_SIZE as u16 + 5));
+        assert!(log.windows(2).all(|pair| pair[1] == pair[0] + 1));
+
+        // Tracing again starts a new log
+        machine.cpu.set_trace(false);
+        machine.cpu.set_trace(true);
+        assert!(machine.cpu.trace_log().is_empty());
+    }
+
+    #[test]
+    fn coverage_counts_the_executed_instructions_by_kind() {
+        // LD V0 3, LD I 0x300 (long), ADD V1 1, SE V1 3, JP 0x206, CLS
+        let mut machine = Machine::new(&[0x60, 0x03, 0xF0, 0x00, 0x03, 0x00, 0x71, 0x01, 0x31, 0x03, 0x12, 0x06, 0x00, 0xE0]);
+        machine.run(1);
+        assert!(machine.cpu.coverage().iter().all(|&count| count == 0));
+
+        machine.cpu.set_profiling(true);
+        machine.run(10);
+        let mut expected = [0; INSTRUCTION_KINDS];
+        expected[Instruction::LdILong(0).kind()] = 1;
+        expected[Instruction::AddByte(1, 1).kind()] = 3;
+        expected[Instruction::SeByte(1, 3).kind()] = 3;
+        expected[Instruction::Jp(0x206).kind()] = 2;
+        expected[Instruction::Cls.kind()] = 1;
+        assert_eq!(machine.cpu.coverage(), &expected);
+
+        // SYS 0 past the end of the program is not counted any more
+        machine.cpu.set_profiling(false);
+        machine.run(1);
+        assert_eq!(machine.cpu.coverage(), &expected);
+    }
+
+    #[test]
+    fn add_to_i_overflows_past_0xfff() {
+        // V0, fx1e_sets_vf, expected I, expected VF
+        let cases: [(u8, bool, u16, u8); 4] = [(0, false, 0xFFF, 7), (1, false, 0x000, 7), (0, true, 0xFFF, 0), (1, true, 0x000, 1)];
+
+        for &(v0, fx1e_sets_vf, expected, vf) in cases.iter() {
+            // ADD I V0
+            let mut machine = Machine::new(&[0xF0, 0x1E]);
+            machine.cpu.set_quirks(Quirks { fx1e_sets_vf, ..Quirks::new() });
+            machine.cpu.i = 0xFFF;
+            machine.cpu.v[0] = v0;
+            machine.cpu.v[0xF] = 7;
+            machine.run(1);
+            assert_eq!((machine.cpu.i, machine.cpu.v[0xF]), (expected, vf), "V0: {}, fx1e_sets_vf: {}", v0, fx1e_sets_vf);
+        }
+    }
+
+    #[test]
+    fn sprites_read_up_to_the_last_byte_of_the_memory() {
+        // DRW V0 V0 1, DRW V0 V0 2
+        let mut machine = Machine::new(&[0xD0, 0x01, 0xD0, 0x02]);
+        machine.ram.write(0xFFF, 0x80);
+        machine.cpu.i = 0xFFF;
+        machine.run(1);
+        assert!(machine.display.pixel(0, 0));
+
+        // The second row would be read past the end of the memory
+        assert_eq!(machine.step(), Err(CpuError::MemoryOutOfBounds(0xFFF)));
+        assert!(machine.display.pixel(0, 0));
+    }
+
+    #[test]
+    fn stores_skip_the_write_protected_area() {
+        // LD [I] V3, LD B V0
+        let mut machine = Machine::new(&[0xF3, 0x55, 0xF0, 0x33]);
+        machine.ram.set_write_protect(Some(crate::ram::RESERVED));
+        machine.cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        machine.cpu.i = 0x1FE;
+        machine.run(1);
+        assert_eq!(machine.ram.bytes()[0x1FE..0x202], [0, 0, 3, 4]);
+
+        machine.cpu.i = 0x100;
+        machine.run(1);
+        assert_eq!(machine.ram.bytes()[0x100..0x103], [0, 0, 0]);
+    }
+
+    #[test]
+    fn the_flag_wins_when_vf_is_the_destination() {
+        // VF, V1, opcode, expected VF
+        let cases: [(u8, u8, u16, u8); 10] = [
+            (200, 100, 0x8F14, 1),
+            (20, 10, 0x8F14, 0),
+            (5, 3, 0x8F15, 1),
+            (3, 5, 0x8F15, 0),
+            (0x05, 0, 0x8F16, 1),
+            (0x04, 0, 0x8F16, 0),
+            (3, 5, 0x8F17, 1),
+            (5, 3, 0x8F17, 0),
+            (0x81, 0, 0x8F1E, 1),
+            (0x41, 0, 0x8F1E, 0),
+        ];
+
+        for &(vf, v1, opcode, expected) in cases.iter() {
+            let mut machine = Machine::new(&opcode.to_be_bytes());
+            machine.cpu.v[0xF] = vf;
+            machine.cpu.v[1] = v1;
+            machine.run(1);
+            assert_eq!(machine.cpu.v[0xF], expected, "VF after {:04X} with VF = {}", opcode, vf);
+        }
+    }
+
+    #[test]
+    fn formats_the_registers_and_the_stack() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0x1F;
+        cpu.v[0xF] = 1;
+        cpu.i = 0x222;
+        cpu.pc = 0x204;
+        cpu.stack[0] = 0x20A;
+        cpu.stack[1] = 0x300;
+        cpu.sp = 1;
+        cpu.st = 0x3C;
+
+        let mut buffer = Buffer::new();
+        write!(buffer, "{:?}", cpu).unwrap();
+        assert_eq!(
+            buffer.as_str(),
+            "V0-V7: 00 1F 00 00 00 00 00 00\n\
+             V8-VF: 00 00 00 00 00 00 00 01\n\
+             I: 0x0222  PC: 0x0204  SP: 1  DT: 00  ST: 3C\n\
+             Stack: 0x020A"
+        );
+    }
+
+    #[test]
+    fn a_debugger_sets_and_reads_the_registers() {
+        let mut cpu = Cpu::new();
+        for x in 0..16 {
+            assert!(cpu.set_register(x, x * 3 + 1));
+        }
+        for x in 0..16 {
+            assert_eq!(cpu.register(x), Some(x * 3 + 1));
+        }
+        assert_eq!(cpu.registers()[0xF], 46);
+
+        assert!(!cpu.set_register(16, 1));
+        assert!(!cpu.set_register(0xFF, 1));
+        assert_eq!(cpu.register(16), None);
+
+        cpu.set_i(0x123);
+        cpu.set_pc(0x456);
+        assert_eq!((cpu.index(), cpu.pc()), (0x123, 0x456));
+    }
 }
@@ -0,0 +1,483 @@
+use core::fmt;
+
+/// A decoded CHIP-8 instruction.
+///
+/// Register operands are stored as register indices (0x0 through 0xF), addresses as 12 bit values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0nnn - SYS addr
+    Sys(u16),
+    /// 00E0 - CLS
+    Cls,
+    /// 00EE - RET
+    Ret,
+    /// 00Cn - SCD nibble (SUPER-CHIP)
+    Scd(u8),
+    /// 00FB - SCR (SUPER-CHIP)
+    Scr,
+    /// 00FC - SCL (SUPER-CHIP)
+    Scl,
+    /// 00FD - EXIT (SUPER-CHIP)
+    Exit,
+    /// 00FE - LOW (SUPER-CHIP)
+    Low,
+    /// 00FF - HIGH (SUPER-CHIP)
+    High,
+    /// 1nnn - JP addr
+    Jp(u16),
+    /// 2nnn - CALL addr
+    Call(u16),
+    /// 3xkk - SE Vx, byte
+    SeByte(u8, u8),
+    /// 4xkk - SNE Vx, byte
+    SneByte(u8, u8),
+    /// 5xy0 - SE Vx, Vy
+    SeReg(u8, u8),
+    /// 6xkk - LD Vx, byte
+    LdByte(u8, u8),
+    /// 7xkk - ADD Vx, byte
+    AddByte(u8, u8),
+    /// 8xy0 - LD Vx, Vy
+    LdReg(u8, u8),
+    /// 8xy1 - OR Vx, Vy
+    Or(u8, u8),
+    /// 8xy2 - AND Vx, Vy
+    And(u8, u8),
+    /// 8xy3 - XOR Vx, Vy
+    Xor(u8, u8),
+    /// 8xy4 - ADD Vx, Vy
+    AddReg(u8, u8),
+    /// 8xy5 - SUB Vx, Vy
+    Sub(u8, u8),
+    /// 8xy6 - SHR Vx {, Vy}
+    Shr(u8, u8),
+    /// 8xy7 - SUBN Vx, Vy
+    Subn(u8, u8),
+    /// 8xyE - SHL Vx {, Vy}
+    Shl(u8, u8),
+    /// 9xy0 - SNE Vx, Vy
+    SneReg(u8, u8),
+    /// Annn - LD I, addr
+    LdI(u16),
+    /// Bnnn - JP V0, addr
+    JpV0(u16),
+    /// Cxkk - RND Vx, byte
+    Rnd(u8, u8),
+    /// Dxyn - DRW Vx, Vy, nibble
+    Drw(u8, u8, u8),
+    /// Ex9E - SKP Vx
+    Skp(u8),
+    /// ExA1 - SKNP Vx
+    Sknp(u8),
+    /// Fn01 - PLANE n (XO-CHIP)
+    Plane(u8),
+    /// Fx07 - LD Vx, DT
+    LdVxDt(u8),
+    /// Fx0A - LD Vx, K
+    LdVxK(u8),
+    /// Fx15 - LD DT, Vx
+    LdDtVx(u8),
+    /// Fx18 - LD ST, Vx
+    LdStVx(u8),
+    /// Fx1E - ADD I, Vx
+    AddIVx(u8),
+    /// Fx29 - LD F, Vx
+    LdFVx(u8),
+    /// Fx30 - LD HF, Vx (SUPER-CHIP)
+    LdHfVx(u8),
+    /// Fx33 - LD B, Vx
+    LdBVx(u8),
+    /// Fx3A - PITCH Vx (XO-CHIP)
+    Pitch(u8),
+    /// Fx55 - LD [I], Vx
+    LdMemVx(u8),
+    /// Fx65 - LD Vx, [I]
+    LdVxMem(u8),
+    /// F000 nnnn - LD I, long addr (XO-CHIP)
+    ///
+    /// The address is stored in the word following the opcode, so `decode` can't produce this, see `dump`.
+    LdILong(u16),
+}
+
+/// Number of different kinds of instructions, see `Instruction::kind`
+pub const INSTRUCTION_KINDS: usize = 45;
+
+impl Instruction {
+    /// Returns the kind of the instruction regardless of its operands, numbered in declaration order from 0
+    pub fn kind(&self) -> usize {
+        match *self {
+            Instruction::Sys(..) => 0,
+            Instruction::Cls => 1,
+            Instruction::Ret => 2,
+            Instruction::Scd(..) => 3,
+            Instruction::Scr => 4,
+            Instruction::Scl => 5,
+            Instruction::Exit => 6,
+            Instruction::Low => 7,
+            Instruction::High => 8,
+            Instruction::Jp(..) => 9,
+            Instruction::Call(..) => 10,
+            Instruction::SeByte(..) => 11,
+            Instruction::SneByte(..) => 12,
+            Instruction::SeReg(..) => 13,
+            Instruction::LdByte(..) => 14,
+            Instruction::AddByte(..) => 15,
+            Instruction::LdReg(..) => 16,
+            Instruction::Or(..) => 17,
+            Instruction::And(..) => 18,
+            Instruction::Xor(..) => 19,
+            Instruction::AddReg(..) => 20,
+            Instruction::Sub(..) => 21,
+            Instruction::Shr(..) => 22,
+            Instruction::Subn(..) => 23,
+            Instruction::Shl(..) => 24,
+            Instruction::SneReg(..) => 25,
+            Instruction::LdI(..) => 26,
+            Instruction::JpV0(..) => 27,
+            Instruction::Rnd(..) => 28,
+            Instruction::Drw(..) => 29,
+            Instruction::Skp(..) => 30,
+            Instruction::Sknp(..) => 31,
+            Instruction::Plane(..) => 32,
+            Instruction::LdVxDt(..) => 33,
+            Instruction::LdVxK(..) => 34,
+            Instruction::LdDtVx(..) => 35,
+            Instruction::LdStVx(..) => 36,
+            Instruction::AddIVx(..) => 37,
+            Instruction::LdFVx(..) => 38,
+            Instruction::LdHfVx(..) => 39,
+            Instruction::LdBVx(..) => 40,
+            Instruction::Pitch(..) => 41,
+            Instruction::LdMemVx(..) => 42,
+            Instruction::LdVxMem(..) => 43,
+            Instruction::LdILong(..) => 44,
+        }
+    }
+}
+
+/// Decodes a single opcode, returns `None` if the opcode is not a valid instruction
+pub fn decode(opcode: u16) -> Option<Instruction> {
+    let nnn = opcode & 0x0FFF;
+    let kk = (opcode & 0x00FF) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+
+    let instruction = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00FB => Instruction::Scr,
+            0x00FC => Instruction::Scl,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Low,
+            0x00FF => Instruction::High,
+            _ if opcode & 0xFFF0 == 0x00C0 => Instruction::Scd(n),
+            _ => Instruction::Sys(nnn),
+        },
+        0x1 => Instruction::Jp(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::SeByte(x, kk),
+        0x4 => Instruction::SneByte(x, kk),
+        0x5 if n == 0 => Instruction::SeReg(x, y),
+        0x6 => Instruction::LdByte(x, kk),
+        0x7 => Instruction::AddByte(x, kk),
+        0x8 => match n {
+            0x0 => Instruction::LdReg(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::Sub(x, y),
+            0x6 => Instruction::Shr(x, y),
+            0x7 => Instruction::Subn(x, y),
+            0xE => Instruction::Shl(x, y),
+            _ => return None,
+        },
+        0x9 if n == 0 => Instruction::SneReg(x, y),
+        0xA => Instruction::LdI(nnn),
+        0xB => Instruction::JpV0(nnn),
+        0xC => Instruction::Rnd(x, kk),
+        0xD => Instruction::Drw(x, y, n),
+        0xE => match kk {
+            0x9E => Instruction::Skp(x),
+            0xA1 => Instruction::Sknp(x),
+            _ => return None,
+        },
+        0xF => match kk {
+            0x01 => Instruction::Plane(x),
+            0x07 => Instruction::LdVxDt(x),
+            0x0A => Instruction::LdVxK(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1E => Instruction::AddIVx(x),
+            0x29 => Instruction::LdFVx(x),
+            0x30 => Instruction::LdHfVx(x),
+            0x33 => Instruction::LdBVx(x),
+            0x3A => Instruction::Pitch(x),
+            0x55 => Instruction::LdMemVx(x),
+            0x65 => Instruction::LdVxMem(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(instruction)
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Sys(addr) => write!(f, "SYS {:#05X}", addr),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jp(addr) => write!(f, "JP {:#05X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL {:#05X}", addr),
+            Instruction::SeByte(x, kk) => write!(f, "SE V{:X}, {:#04X}", x, kk),
+            Instruction::SneByte(x, kk) => write!(f, "SNE V{:X}, {:#04X}", x, kk),
+            Instruction::SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdByte(x, kk) => write!(f, "LD V{:X}, {:#04X}", x, kk),
+            Instruction::AddByte(x, kk) => write!(f, "ADD V{:X}, {:#04X}", x, kk),
+            Instruction::LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0, {:#05X}", addr),
+            Instruction::Rnd(x, kk) => write!(f, "RND V{:X}, {:#04X}", x, kk),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdMemVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxMem(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdILong(addr) => write!(f, "LD I, {:#06X}", addr),
+        }
+    }
+}
+
+/// Disassembles a ROM, writing one addressed mnemonic per line into `out`.
+///
+/// `start` is the address the ROM is loaded to, usually 0x200.
+/// Words which are not valid instructions (and a trailing odd byte) are written as `DB` data.
+/// The XO-CHIP long load takes two words, and is written on a single line.
+pub fn dump<W: fmt::Write>(out: &mut W, rom: &[u8], start: u16) -> fmt::Result {
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = start as usize + offset;
+        let word = &rom[offset..rom.len().min(offset + 2)];
+        offset += 2;
+        if word.len() < 2 {
+            writeln!(out, "{:#05X}: {:02X}    DB {:#04X}", address, word[0], word[0])?;
+            continue;
+        }
+
+        let opcode = (word[0] as u16) << 8 | (word[1] as u16);
+        if opcode == 0xF000 && offset + 2 <= rom.len() {
+            let addr = (rom[offset] as u16) << 8 | (rom[offset + 1] as u16);
+            offset += 2;
+            writeln!(out, "{:#05X}: {:04X}  {}", address, opcode, Instruction::LdILong(addr))?;
+            continue;
+        }
+
+        match decode(opcode) {
+            Some(instruction) => writeln!(out, "{:#05X}: {:04X}  {}", address, opcode, instruction)?,
+            None => writeln!(out, "{:#05X}: {:04X}  DB {:#04X}, {:#04X}", address, opcode, word[0], word[1])?,
+        }
+    }
+
+    Ok(())
+}
+
+/// The CHIP-8 variants, from the least to the most advanced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Variant {
+    /// The original COSMAC VIP CHIP-8
+    ClassicChip8,
+
+    /// SUPER-CHIP: high resolution, scrolling, big font
+    SuperChip,
+
+    /// XO-CHIP: bit-planes, 64 kb memory, audio
+    XoChip,
+}
+
+/// Guesses the variant a ROM was written for from the opcodes it contains, e.g. to choose the quirks.
+///
+/// Every word at an even offset is checked for opcodes which only exist in SUPER-CHIP
+/// (00Cn, 00FB-00FF, Dxy0, Fx30, Fx75, Fx85) or in XO-CHIP (00Dn, 5xy2, 5xy3, F000, Fn01, F002, Fx3A),
+/// and the most advanced variant found is returned. Data mixed in the code may look like such an opcode,
+/// so this is only a hint. Nothing is executed.
+pub fn detect_variant(rom: &[u8]) -> Variant {
+    rom.chunks_exact(2)
+        .map(|word| {
+            let opcode = (word[0] as u16) << 8 | (word[1] as u16);
+            match opcode & 0xF000 {
+                0x0000 if opcode & 0xFFF0 == 0x00D0 => Variant::XoChip,
+                0x0000 if opcode & 0xFFF0 == 0x00C0 || (0x00FB..=0x00FF).contains(&opcode) => Variant::SuperChip,
+                0x5000 if matches!(opcode & 0x000F, 0x2 | 0x3) => Variant::XoChip,
+                0xD000 if opcode & 0x000F == 0 => Variant::SuperChip,
+                0xF000 if opcode == 0xF000 || opcode == 0xF002 || opcode & 0xF0FF == 0xF001 || opcode & 0xF0FF == 0xF03A => Variant::XoChip,
+                0xF000 if matches!(opcode & 0x00FF, 0x30 | 0x75 | 0x85) => Variant::SuperChip,
+                _ => Variant::ClassicChip8,
+            }
+        })
+        .max()
+        .unwrap_or(Variant::ClassicChip8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use core::str;
+
+    /// Collects formatted text without allocating
+    struct Buffer {
+        bytes: [u8; 512],
+        len: usize,
+    }
+
+    impl Buffer {
+        fn new() -> Buffer {
+            Buffer { bytes: [0; 512], len: 0 }
+        }
+
+        fn as_str(&self) -> &str {
+            str::from_utf8(&self.bytes[..self.len]).unwrap()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decodes_every_opcode_family() {
+        let cases: [(u16, &str); 45] = [
+            (0x0123, "SYS 0x123"),
+            (0x00E0, "CLS"),
+            (0x00EE, "RET"),
+            (0x00C4, "SCD 4"),
+            (0x00FB, "SCR"),
+            (0x00FC, "SCL"),
+            (0x00FD, "EXIT"),
+            (0x00FE, "LOW"),
+            (0x00FF, "HIGH"),
+            (0x1ABC, "JP 0xABC"),
+            (0x2ABC, "CALL 0xABC"),
+            (0x3A42, "SE VA, 0x42"),
+            (0x4A42, "SNE VA, 0x42"),
+            (0x5AB0, "SE VA, VB"),
+            (0x6A42, "LD VA, 0x42"),
+            (0x7A42, "ADD VA, 0x42"),
+            (0x8AB0, "LD VA, VB"),
+            (0x8AB1, "OR VA, VB"),
+            (0x8AB2, "AND VA, VB"),
+            (0x8AB3, "XOR VA, VB"),
+            (0x8AB4, "ADD VA, VB"),
+            (0x8AB5, "SUB VA, VB"),
+            (0x8AB6, "SHR VA, VB"),
+            (0x8AB7, "SUBN VA, VB"),
+            (0x8ABE, "SHL VA, VB"),
+            (0x9AB0, "SNE VA, VB"),
+            (0xA2EA, "LD I, 0x2EA"),
+            (0xB2EA, "JP V0, 0x2EA"),
+            (0xCA0F, "RND VA, 0x0F"),
+            (0xD125, "DRW V1, V2, 5"),
+            (0xEA9E, "SKP VA"),
+            (0xEAA1, "SKNP VA"),
+            (0xF201, "PLANE 2"),
+            (0xFA07, "LD VA, DT"),
+            (0xFA0A, "LD VA, K"),
+            (0xFA15, "LD DT, VA"),
+            (0xFA18, "LD ST, VA"),
+            (0xFA1E, "ADD I, VA"),
+            (0xFA29, "LD F, VA"),
+            (0xFA30, "LD HF, VA"),
+            (0xFA33, "LD B, VA"),
+            (0xFA3A, "PITCH VA"),
+            (0xFA55, "LD [I], VA"),
+            (0xFA65, "LD VA, [I]"),
+            (0x0000, "SYS 0x000"),
+        ];
+
+        let mut kinds = [false; INSTRUCTION_KINDS];
+        for &(opcode, text) in cases.iter() {
+            let instruction = decode(opcode).unwrap();
+            let mut buffer = Buffer::new();
+            write!(buffer, "{}", instruction).unwrap();
+            assert_eq!(buffer.as_str(), text, "opcode {:04X}", opcode);
+            kinds[instruction.kind()] = true;
+        }
+        // Every kind but the long load, which takes two words
+        assert_eq!(kinds.iter().filter(|&&seen| seen).count(), INSTRUCTION_KINDS - 1);
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for &opcode in [0x5AB1u16, 0x8AB8, 0x8ABF, 0x9AB1, 0xEA00, 0xFA00, 0xFAFF].iter() {
+            assert_eq!(decode(opcode), None, "opcode {:04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn dumps_unknown_words_as_data() {
+        let rom = [0x00, 0xE0, 0xFF, 0xFF, 0xF0, 0x00, 0x12, 0x34, 0xAB];
+        let mut buffer = Buffer::new();
+        dump(&mut buffer, &rom, 0x200).unwrap();
+        assert_eq!(
+            buffer.as_str(),
+            "0x200: 00E0  CLS\n\
+             0x202: FFFF  DB 0xFF, 0xFF\n\
+             0x204: F000  LD I, 0x1234\n\
+             0x208: AB    DB 0xAB\n"
+        );
+    }
+
+    #[test]
+    fn detects_the_most_advanced_variant() {
+        let cases: [(&[u8], Variant); 9] = [
+            (&[], Variant::ClassicChip8),
+            (&[0x60, 0x01, 0xD0, 0x15, 0x12, 0x00], Variant::ClassicChip8),
+            (&[0x60, 0x01, 0x00, 0xFF], Variant::SuperChip),
+            (&[0xD0, 0x10], Variant::SuperChip),
+            (&[0xF3, 0x30], Variant::SuperChip),
+            (&[0x00, 0xC4, 0xF0, 0x00, 0x12, 0x34], Variant::XoChip),
+            (&[0xF2, 0x01, 0x00, 0xFF], Variant::XoChip),
+            (&[0x51, 0x22], Variant::XoChip),
+            // Only the words at even offsets are opcodes
+            (&[0x60, 0x00, 0xFF, 0x00], Variant::ClassicChip8),
+        ];
+
+        for &(rom, variant) in cases.iter() {
+            assert_eq!(detect_variant(rom), variant, "ROM {:02X?}", rom);
+        }
+    }
+}
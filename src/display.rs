@@ -1,78 +1,539 @@
-use crate::vga_13h_buffer;
+use core::ops::Range;
+
 use crate::color::Color;
+use crate::quirks::Quirks;
+use crate::renderer::{Renderer, Vga13hRenderer};
+use crate::vga_13h_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// Resolution of the classic CHIP-8 display
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+
+/// Resolution of the SUPER-CHIP high resolution mode, the framebuffer is sized for this mode
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+/// Pixels of the screen indexed by row then column, sized for the high resolution mode
+pub type Framebuffer = [[bool; HIRES_WIDTH]; HIRES_HEIGHT];
+
+/// Number of bit-planes of the XO-CHIP display, the classic display only uses the first one
+pub const PLANES: usize = 2;
+
+/// Colors of the pixels by plane bits when the display is created: off, first plane, second plane, both planes
+pub const DEFAULT_PALETTE: [Color; 4] = [Color::Black, Color::White, Color::LightRed, Color::Yellow];
+
+/// Size of the 64x32 low resolution screen packed into bytes, 8 pixels per byte
+pub const BITMAP_SIZE: usize = LORES_WIDTH * LORES_HEIGHT / 8;
+
+/// Default scaling of the low resolution mode on the 320x200 output, the largest which fits
+pub const DEFAULT_SCALE: usize = 5;
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
-const MULTIPLIER: usize = 5;
+/// Collisions of a drawn sprite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Collision {
+    /// Number of rows of the sprite which erased at least one pixel
+    pub rows: u8,
 
-pub struct Display {
-    color: Color
+    /// Number of rows of the sprite which were clipped at the bottom of the screen
+    pub clipped_rows: u8,
+}
+
+impl Collision {
+    /// Returns true if any pixel was erased
+    pub fn any(&self) -> bool {
+        self.rows > 0
+    }
+}
+
+pub struct Display<R: Renderer = Vga13hRenderer> {
+    renderer: R,
+
+    /// Colors of the pixels, indexed by the bits of the pixel in the planes (bit 0 is the first plane)
+    palette: [Color; 4],
+
+    /// Bitmask of the planes affected by drawing, clearing and scrolling, bit 0 is the first plane
+    selected_planes: u8,
+
+    /// SUPER-CHIP high resolution mode is active
+    hires: bool,
+
+    quirks: Quirks,
+
+    /// Size of a low resolution CHIP-8 pixel on the output, high resolution pixels are half as big
+    scale: usize,
+
+    /// The framebuffer of each plane, the source of truth for the pixels on the screen.
+    /// In low resolution mode only the top-left 64x32 pixels are used.
+    planes: [Framebuffer; PLANES],
+
+    /// Colors of the pixels written to the output by the last present, indexed by row then column
+    presented: [[Color; HIRES_WIDTH]; HIRES_HEIGHT],
+
+    /// `presented` matches the output, otherwise `present_diff` writes every pixel
+    baseline: bool,
+
+    /// The screen may have changed since the last complete present
+    dirty: bool,
+
+    /// Mirror the image on the output horizontally and vertically, the framebuffer is not affected
+    flip_h: bool,
+    flip_v: bool,
 }
 
 impl Display {
-    /// Creates a new display with the given foreground color
+    /// Creates a new display rendering to VGA with the given foreground color
     pub fn new(color: Color) -> Display {
+        Display::with_renderer(color, Vga13hRenderer::new())
+    }
+}
+
+impl<R: Renderer> Display<R> {
+    /// Creates a new display rendering to the given renderer with the given foreground color
+    pub fn with_renderer(color: Color, renderer: R) -> Display<R> {
+        let mut palette = DEFAULT_PALETTE;
+        palette[1] = color;
+
         Display {
-            color
+            renderer,
+            palette,
+            selected_planes: 0b01,
+            hires: false,
+            quirks: Quirks::new(),
+            scale: DEFAULT_SCALE,
+            planes: [[[false; HIRES_WIDTH]; HIRES_HEIGHT]; PLANES],
+            presented: [[Color::Black; HIRES_WIDTH]; HIRES_HEIGHT],
+            baseline: false,
+            dirty: true,
+            flip_h: false,
+            flip_v: false,
         }
     }
 
-    /// Clears the screen
-    pub fn clear(&mut self) {
-        for i in 0..WIDTH {
-            for j in 0..HEIGHT {
-                self.set_pixel(i, j, Color::Black);
+    /// Returns the renderer the display is drawn to
+    pub fn renderer(&self) -> &R {
+        &self.renderer
+    }
+
+    /// Sets the color of the pixels which are on and off in the first plane
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.palette[1] = fg;
+        self.palette[0] = bg;
+        self.dirty = true;
+    }
+
+    /// Sets the colors of the pixels, indexed by the bits of the pixel in the planes:
+    /// off, on in the first plane, on in the second plane, on in both planes
+    pub fn set_palette(&mut self, palette: [Color; 4]) {
+        self.palette = palette;
+        self.dirty = true;
+    }
+
+    /// Returns the colors of the pixels, indexed by the bits of the pixel in the planes
+    pub fn palette(&self) -> [Color; 4] {
+        self.palette
+    }
+
+    /// Selects the planes affected by drawing, clearing and scrolling, bit 0 is the first plane.
+    /// The classic display uses only the first plane, XO-CHIP programs select the planes with Fn01.
+    pub fn select_planes(&mut self, mask: u8) {
+        self.selected_planes = mask & 0b11;
+    }
+
+    /// Returns the bitmask of the selected planes
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    /// Returns the number of selected planes, a sprite holds the rows of each selected plane one after the other
+    pub fn selected_plane_count(&self) -> usize {
+        self.selected_planes.count_ones() as usize
+    }
+
+    /// Sets the interpreter quirks affecting how sprites are drawn
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Sets the size of a low resolution CHIP-8 pixel on the output, high resolution pixels are half as big.
+    /// The scale is clamped so the image always fits the output, the image is centered on the output.
+    pub fn set_scale(&mut self, scale: usize) {
+        let max = (BUFFER_WIDTH / LORES_WIDTH).min(BUFFER_HEIGHT / LORES_HEIGHT);
+        self.blank();
+        self.scale = scale.max(1).min(max);
+    }
+
+    /// Returns the size of a low resolution CHIP-8 pixel on the output
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Mirrors the image on the output horizontally and/or vertically, e.g. for a projector showing it mirrored.
+    /// Only the output is mirrored: the framebuffer, and so the collisions, stay the same.
+    pub fn set_orientation(&mut self, flip_h: bool, flip_v: bool) {
+        self.flip_h = flip_h;
+        self.flip_v = flip_v;
+        self.invalidate();
+    }
+
+    /// Returns the position of the top-left corner of the given CHIP-8 pixel on the output, after mirroring.
+    /// Coordinates outside of the active resolution wrap around, like the start of a sprite.
+    pub fn output_position(&self, x: usize, y: usize) -> (usize, usize) {
+        let multiplier = self.multiplier();
+        let x = x % self.width();
+        let y = y % self.height();
+        let x = if self.flip_h { self.width() - 1 - x } else { x };
+        let y = if self.flip_v { self.height() - 1 - y } else { y };
+        let (left, top) = self.image_origin();
+        (left + x * multiplier, top + y * multiplier)
+    }
+
+    /// Width of the display in the active resolution
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { LORES_WIDTH }
+    }
+
+    /// Height of the display in the active resolution
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { LORES_HEIGHT }
+    }
+
+    /// Returns true if the SUPER-CHIP high resolution mode is active
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switches between the 64x32 and the SUPER-CHIP 128x64 resolution, clearing the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        // The two resolutions cover a different area of the output,
+        // so the area of the old resolution has to be blanked.
+        self.blank();
+        self.hires = hires;
+        self.planes = [[[false; HIRES_WIDTH]; HIRES_HEIGHT]; PLANES];
+    }
+
+    /// Returns the pixels of the first plane, indexed by row then column.
+    /// This is the whole screen, unless an XO-CHIP program draws to the second plane.
+    pub fn pixels(&self) -> &Framebuffer {
+        &self.planes[0]
+    }
+
+    /// Returns the pixels of the given plane, indexed by row then column
+    pub fn plane(&self, plane: usize) -> &Framebuffer {
+        &self.planes[plane]
+    }
+
+    /// Returns true if the pixel at the given x,y coordinates is on in the first plane
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.planes[0][y][x]
+    }
+
+    /// Returns the palette index of the pixel at the given x,y coordinates, bit n is set if the pixel is on in plane n
+    pub fn color_index(&self, x: usize, y: usize) -> usize {
+        (0..PLANES).filter(|&plane| self.planes[plane][y][x]).map(|plane| 1 << plane).sum()
+    }
+
+    /// Replaces the content of the first plane with the given pixels
+    pub fn load_pixels(&mut self, pixels: &Framebuffer) {
+        self.load_plane(0, pixels);
+    }
+
+    /// Replaces the content of the given plane with the given pixels
+    pub fn load_plane(&mut self, plane: usize, pixels: &Framebuffer) {
+        self.planes[plane] = *pixels;
+        self.dirty = true;
+    }
+
+    /// Packs the 64x32 low resolution screen of the first plane into a 1 bit per pixel bitmap.
+    /// The pixels are stored row by row, 8 pixels per byte, the most significant bit is the leftmost pixel.
+    pub fn as_bitmap(&self) -> [u8; BITMAP_SIZE] {
+        let mut bitmap = [0; BITMAP_SIZE];
+        for y in 0..LORES_HEIGHT {
+            for x in 0..LORES_WIDTH {
+                if self.planes[0][y][x] {
+                    let bit = y * LORES_WIDTH + x;
+                    bitmap[bit / 8] |= 0x80 >> (bit % 8);
+                }
             }
         }
+
+        bitmap
+    }
+
+    /// Replaces the 64x32 low resolution screen of the first plane with the pixels of a bitmap packed by `as_bitmap`
+    pub fn load_bitmap(&mut self, bitmap: &[u8; BITMAP_SIZE]) {
+        for y in 0..LORES_HEIGHT {
+            for x in 0..LORES_WIDTH {
+                let bit = y * LORES_WIDTH + x;
+                self.planes[0][y][x] = bitmap[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Clears the selected planes. The image on the output is filled with the background color as well,
+    /// so no scaled up pixel is left there until the next `present`.
+    pub fn clear(&mut self) {
+        for plane in self.selected() {
+            self.planes[plane] = [[false; HIRES_WIDTH]; HIRES_HEIGHT];
+        }
+        self.fill_image(self.palette[0]);
+    }
+
+    /// Fills the whole output with black, including the border around the image
+    pub fn blank_output(&mut self) {
+        self.renderer.begin();
+        self.renderer.fill_rect(0, 0, BUFFER_WIDTH, BUFFER_HEIGHT, Color::Black);
+        self.renderer.present();
+        self.invalidate();
     }
 
-    /// Draws a sprite to the given x,y coordinates
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
-        let mut collision = false;
-        for row in 0..sprite.len() {
-            let row_bytes = sprite[row];
-            for column in 0..8 {
-                let new_value = (row_bytes >> (7 - column)) & 0x01;
-                if new_value == 1 {
-                    let real_x = (x + column as usize) % WIDTH;
-                    let real_y = (y + row as usize) % HEIGHT;
-                    collision |= self.xor_pixel(real_x, real_y, self.color);
+    /// Writes a hexadecimal digit of the font straight to the output at the given output coordinates, bypassing the framebuffer.
+    /// One pixel of the 4x5 glyph is one pixel of the output, this is used to show text around the image.
+    pub fn write_digit(&mut self, x: usize, y: usize, digit: u8, color: Color) {
+        let from = (digit & 0x0F) as usize * 5;
+        for (row, bits) in FONT[from..from + 5].iter().enumerate() {
+            for column in 0..4 {
+                let on = bits & (0x80 >> column) != 0;
+                if x + column < BUFFER_WIDTH && y + row < BUFFER_HEIGHT {
+                    self.renderer.set(x + column, y + row, if on { color } else { Color::Black });
                 }
             }
         }
+    }
+
+    /// Copies the framebuffer to the renderer
+    pub fn present(&mut self) {
+        self.present_rows(0..self.height());
+    }
+
+    /// Copies the given rows of the framebuffer to the renderer, e.g. only the rows a sprite touched.
+    /// The rows are written in a single batch, see `Renderer::begin`: the VGA writer is locked once
+    /// instead of once for each run of same colored pixels, which could be up to 2048 locks per frame.
+    pub fn present_rows(&mut self, rows: Range<usize>) {
+        let rows = rows.start.min(self.height())..rows.end.min(self.height());
+        self.write_rows(rows, false);
+    }
+
+    /// Returns true if the screen may have changed since the last complete present, so a host loop
+    /// can skip presenting a static screen. Every sprite drawn marks the screen as changed,
+    /// even if it left the pixels as they were.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Copies only the pixels which changed since the last present to the renderer, which is much less
+    /// traffic for a mostly static screen. Everything is copied if the output was changed some other way
+    /// since the last present, e.g. by `clear` or `set_hires`.
+    pub fn present_diff(&mut self) {
+        self.write_rows(0..self.height(), self.baseline);
+    }
+
+    /// Scrolls the selected planes down by `n` pixels, the vacated rows are cleared
+    pub fn scroll_down(&mut self, n: usize) {
+        let height = self.height();
+        let n = n.min(height);
+        self.dirty = true;
+        for plane in self.selected() {
+            let pixels = &mut self.planes[plane][..height];
+            pixels.copy_within(..height - n, n);
+            for row in pixels[..n].iter_mut() {
+                *row = [false; HIRES_WIDTH];
+            }
+        }
+    }
+
+    /// Scrolls the selected planes right by 4 pixels, the vacated columns are cleared
+    pub fn scroll_right(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        self.dirty = true;
+        for plane in self.selected() {
+            for row in self.planes[plane][..height].iter_mut() {
+                row.copy_within(..width - 4, 4);
+                row[..4].copy_from_slice(&[false; 4]);
+            }
+        }
+    }
+
+    /// Scrolls the selected planes left by 4 pixels, the vacated columns are cleared
+    pub fn scroll_left(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        self.dirty = true;
+        for plane in self.selected() {
+            for row in self.planes[plane][..height].iter_mut() {
+                row.copy_within(4..width, 0);
+                row[width - 4..width].copy_from_slice(&[false; 4]);
+            }
+        }
+    }
+
+    /// Draws a sprite to the given x,y coordinates.
+    ///
+    /// The coordinates always wrap around, so a sprite starting off the screen is drawn on the opposite side.
+    /// The part of the sprite crossing the edge of the screen is clipped, unless the `wrap_sprites` quirk is set.
+    /// Clipped pixels are not drawn and never collide.
+    ///
+    /// The sprite is drawn to each selected plane, holding the rows of the selected planes one after the other.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> Collision {
+        let (x, y) = (x % self.width(), y % self.height());
+        let mut collision = Collision { rows: 0, clipped_rows: 0 };
+        self.dirty = true;
+        let height = sprite.len() / self.selected_plane_count().max(1);
+        for (index, plane) in self.selected().enumerate() {
+            let rows = &sprite[index * height..(index + 1) * height];
+            for (row, &row_bits) in rows.iter().enumerate() {
+                self.draw_row(plane, x, y + row, (row_bits as u16) << 8, 8, &mut collision);
+            }
+        }
 
         collision
     }
 
-    fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
-        let mut writer = vga_13h_buffer::WRITER.lock();
-        for i in 0..MULTIPLIER {
-            for j in 0..MULTIPLIER {
-                writer.write_byte(
-                    (x * MULTIPLIER + i) as u16,
-                    (y * MULTIPLIER + j) as u16,
-                    color as u8);
+    /// Draws a SUPER-CHIP 16x16 sprite to the given x,y coordinates, wrapping and clipping like `draw`.
+    /// Each row of the sprite is stored in two bytes, the first byte being the left half.
+    /// The sprite holds 32 bytes for each selected plane, one plane after the other.
+    pub fn draw_large(&mut self, x: usize, y: usize, sprite: &[u8]) -> Collision {
+        let (x, y) = (x % self.width(), y % self.height());
+        let mut collision = Collision { rows: 0, clipped_rows: 0 };
+        self.dirty = true;
+        for (index, plane) in self.selected().enumerate() {
+            let rows = &sprite[index * 32..(index + 1) * 32];
+            for row in 0..16 {
+                let row_bits = (rows[row * 2] as u16) << 8 | rows[row * 2 + 1] as u16;
+                self.draw_row(plane, x, y + row, row_bits, 16, &mut collision);
             }
         }
+
+        collision
     }
 
-    fn xor_pixel(&mut self, x: usize, y: usize, color: Color) -> bool {
-        let mut collision = false;
-        let mut writer = vga_13h_buffer::WRITER.lock();
+    /// The indices of the selected planes
+    fn selected(&self) -> impl Iterator<Item = usize> {
+        let mask = self.selected_planes;
+        (0..PLANES).filter(move |&plane| mask & (1 << plane) != 0)
+    }
 
-        // Chip8 video expects a 64x32 screen, but we have a 320x200 so each pixel must be
-        // roughly 5 times bigger on our screen.
-        for i in 0..MULTIPLIER {
-            for j in 0..MULTIPLIER {
-                collision |= writer.xor_byte(
-                    (x * MULTIPLIER + i) as u16,
-                    (y * MULTIPLIER + j) as u16,
-                    color as u8);
+    /// XORs the `width` most significant bits of `row_bits` onto the plane at the given x,y coordinates,
+    /// counting the row in `collision` if it erased a pixel or if it was clipped
+    fn draw_row(&mut self, plane: usize, x: usize, y: usize, row_bits: u16, width: usize, collision: &mut Collision) {
+        if y >= self.height() && !self.quirks.wrap_sprites {
+            collision.clipped_rows += 1;
+            return;
+        }
+
+        let mut erased = false;
+        for column in 0..width {
+            let new_value = (row_bits >> (15 - column)) & 0x01;
+            if new_value == 1 {
+                if x + column >= self.width() && !self.quirks.wrap_sprites {
+                    break;
+                }
+
+                let real_x = (x + column) % self.width();
+                let real_y = y % self.height();
+                erased |= self.xor_pixel(plane, real_x, real_y);
             }
         }
 
+        if erased {
+            collision.rows += 1;
+        }
+    }
+
+    /// Size of a CHIP-8 pixel on the output in the active resolution
+    fn multiplier(&self) -> usize {
+        // Chip8 video expects a 64x32 screen, but we have a 320x200 so each pixel must be
+        // scaled up (by 5 by default, half of that in the 128x64 high resolution mode),
+        // and the image is centered on the screen.
+        if self.hires { (self.scale / 2).max(1) } else { self.scale }
+    }
+
+    /// Flips the pixel of the plane at the given x,y coordinates, returns true if the pixel was erased
+    fn xor_pixel(&mut self, plane: usize, x: usize, y: usize) -> bool {
+        let collision = self.planes[plane][y][x];
+        self.planes[plane][y][x] = !collision;
         collision
     }
+
+    /// Fills the area of the active resolution with black on the output
+    fn blank(&mut self) {
+        self.fill_image(Color::Black);
+    }
+
+    /// Position of the top-left corner of the image on the output, the image is centered
+    fn image_origin(&self) -> (usize, usize) {
+        let multiplier = self.multiplier();
+        let offset_x = BUFFER_WIDTH.saturating_sub(self.width() * multiplier) / 2;
+        let offset_y = BUFFER_HEIGHT.saturating_sub(self.height() * multiplier) / 2;
+        (offset_x, offset_y)
+    }
+
+    /// Writes the given rows of the framebuffer to the renderer, or only the pixels which differ from `presented`
+    fn write_rows(&mut self, rows: Range<usize>, only_changed: bool) {
+        // `presented` only matches the whole output if every row was written
+        let complete = rows == (0..self.height());
+
+        // Neighbouring pixels of the same color in a row are written as one rectangle,
+        // so a mostly empty screen costs a few fills per row instead of one per pixel.
+        let multiplier = self.multiplier();
+        self.renderer.begin();
+        for y in rows {
+            let mut x = 0;
+            while x < self.width() {
+                let color = self.palette[self.color_index(x, y)];
+                let start = x;
+                while x < self.width()
+                    && self.palette[self.color_index(x, y)] == color
+                    && !(only_changed && self.presented[y][x] == color)
+                {
+                    self.presented[y][x] = color;
+                    x += 1;
+                }
+                if x == start {
+                    // Unchanged pixel
+                    x += 1;
+                    continue;
+                }
+                // When mirrored horizontally the last pixel of the run is the leftmost one on the output
+                let (first, top) = self.output_position(start, y);
+                let (last, _) = self.output_position(x - 1, y);
+                self.fill_output(first.min(last), top, (x - start) * multiplier, multiplier, color);
+            }
+        }
+        if complete {
+            self.baseline = true;
+            self.dirty = false;
+        }
+        self.renderer.present();
+    }
+
+    /// Fills the area of the active resolution with the given color on the output, in a batch of its own
+    fn fill_image(&mut self, color: Color) {
+        let multiplier = self.multiplier();
+        let (left, top) = self.image_origin();
+        self.renderer.begin();
+        self.fill_output(left, top, self.width() * multiplier, self.height() * multiplier, color);
+        self.renderer.present();
+        self.invalidate();
+    }
+
+    /// Marks the output as changed some other way than through `presented`, so everything is presented again
+    fn invalidate(&mut self) {
+        self.baseline = false;
+        self.dirty = true;
+    }
+
+    /// Fills the rectangle of the output with the given color, clipped to the output,
+    /// so a scaled pixel never ends up outside of the VGA buffer
+    fn fill_output(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        if x >= BUFFER_WIDTH || y >= BUFFER_HEIGHT {
+            return;
+        }
+        let width = width.min(BUFFER_WIDTH - x);
+        let height = height.min(BUFFER_HEIGHT - y);
+        if width > 0 && height > 0 {
+            self.renderer.fill_rect(x, y, width, height, color);
+        }
+    }
 }
 
 pub static FONT: [u8; 80] = [
@@ -93,3 +554,372 @@ pub static FONT: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
+
+/// Address of the SUPER-CHIP big font in memory, right after the font at its default address
+pub const BIG_FONT_BASE: usize = 0x50;
+
+/// SUPER-CHIP 8x10 font of the digits 0 through 9, each glyph is 10 bytes long
+pub static BIG_FONT: [u8; 100] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C  // 9
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{BufferRenderer, NullRenderer};
+
+    fn display() -> Display<NullRenderer> {
+        Display::with_renderer(Color::White, NullRenderer)
+    }
+
+    /// Counts the batches and the writes, and which output pixels were written
+    struct RecordingRenderer {
+        begins: usize,
+        presents: usize,
+        writes: usize,
+        writes_outside_batch: usize,
+        written: [[bool; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        in_batch: bool,
+    }
+
+    impl RecordingRenderer {
+        fn new() -> RecordingRenderer {
+            RecordingRenderer {
+                begins: 0,
+                presents: 0,
+                writes: 0,
+                writes_outside_batch: 0,
+                written: [[false; BUFFER_WIDTH]; BUFFER_HEIGHT],
+                in_batch: false,
+            }
+        }
+
+        /// Forgets everything recorded so far
+        fn reset(&mut self) {
+            *self = RecordingRenderer::new();
+        }
+    }
+
+    impl Renderer for RecordingRenderer {
+        fn begin(&mut self) {
+            self.begins += 1;
+            self.in_batch = true;
+        }
+
+        fn set(&mut self, x: usize, y: usize, color: Color) {
+            self.fill_rect(x, y, 1, 1, color);
+        }
+
+        fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, _color: Color) {
+            self.writes += 1;
+            if !self.in_batch {
+                self.writes_outside_batch += 1;
+            }
+            for row in self.written[y..y + height].iter_mut() {
+                for pixel in row[x..x + width].iter_mut() {
+                    *pixel = true;
+                }
+            }
+        }
+
+        fn present(&mut self) {
+            self.presents += 1;
+            self.in_batch = false;
+        }
+    }
+
+    #[test]
+    fn draws_to_the_selected_planes() {
+        let mut display = display();
+        display.select_planes(0b10);
+        display.draw(0, 0, &[0x80]);
+        assert!(!display.plane(0)[0][0]);
+        assert!(display.plane(1)[0][0]);
+        assert_eq!(display.color_index(0, 0), 2);
+
+        // The sprite holds the rows of the first plane, then the rows of the second plane
+        display.select_planes(0b11);
+        display.draw(0, 0, &[0x40, 0x80]);
+        assert!(display.plane(0)[0][1]);
+        assert!(!display.plane(1)[0][0]);
+        assert_eq!(display.color_index(1, 0), 1);
+        assert_eq!(display.color_index(0, 0), 0);
+
+        display.select_planes(0b00);
+        let collision = display.draw(0, 0, &[0xFF]);
+        assert!(!collision.any());
+        assert_eq!(display.color_index(1, 0), 1);
+    }
+
+    #[test]
+    fn presents_rows_in_a_single_batch() {
+        let mut display = Display::with_renderer(Color::White, RecordingRenderer::new());
+        display.draw(0, 4, &[0xAA, 0x55, 0xAA, 0x55]);
+        display.renderer.reset();
+
+        display.present_rows(4..8);
+        let renderer = display.renderer();
+        assert_eq!((renderer.begins, renderer.presents), (1, 1));
+        assert_eq!(renderer.writes_outside_batch, 0);
+        assert!(renderer.writes > 4);
+
+        // Only the output rows of the given CHIP-8 rows are written
+        let (_, top) = display.output_position(0, 4);
+        let (_, bottom) = display.output_position(0, 8);
+        for (row, written) in renderer.written.iter().enumerate() {
+            assert_eq!(written.iter().any(|&pixel| pixel), (top..bottom).contains(&row), "output row {}", row);
+        }
+
+        // Clearing writes the output in a batch as well
+        display.renderer.reset();
+        display.clear();
+        display.blank_output();
+        display.set_hires(true);
+        let renderer = display.renderer();
+        assert_eq!((renderer.begins, renderer.presents), (3, 3));
+        assert_eq!(renderer.writes_outside_batch, 0);
+    }
+
+    #[test]
+    fn present_diff_writes_only_the_changed_pixels() {
+        let mut display = Display::with_renderer(Color::White, RecordingRenderer::new());
+        display.draw(0, 0, &[0x80]);
+        display.present();
+        display.renderer.reset();
+
+        // Move the pixel from (0, 0) to (5, 3)
+        display.draw(0, 0, &[0x80]);
+        display.draw(5, 3, &[0x80]);
+        display.present_diff();
+
+        let multiplier = display.multiplier();
+        let changed = [display.output_position(0, 0), display.output_position(5, 3)];
+        for (y, row) in display.renderer().written.iter().enumerate() {
+            for (x, &written) in row.iter().enumerate() {
+                let expected = changed
+                    .iter()
+                    .any(|&(left, top)| (left..left + multiplier).contains(&x) && (top..top + multiplier).contains(&y));
+                assert_eq!(written, expected, "output pixel ({}, {})", x, y);
+            }
+        }
+        assert_eq!(display.renderer().writes, 2);
+    }
+
+    #[test]
+    fn presenting_clears_the_dirty_flag() {
+        let mut display = display();
+        assert!(display.is_dirty());
+        display.present();
+        assert!(!display.is_dirty());
+
+        display.draw(3, 3, &[0xF0]);
+        assert!(display.is_dirty());
+        display.present();
+        assert!(!display.is_dirty());
+
+        // Drawing the sprite twice leaves the pixels as they were, but still marks the screen
+        display.draw(8, 8, &[0xFF]);
+        display.draw(8, 8, &[0xFF]);
+        assert!(display.is_dirty());
+        display.present_diff();
+        assert!(!display.is_dirty());
+
+        display.scroll_down(1);
+        assert!(display.is_dirty());
+        display.present_rows(0..4);
+        assert!(display.is_dirty());
+        display.present();
+
+        display.clear();
+        assert!(display.is_dirty());
+        display.present();
+        assert!(!display.is_dirty());
+    }
+
+    #[test]
+    fn mirrors_the_output_but_not_the_framebuffer() {
+        let mut display = Display::with_renderer(Color::White, BufferRenderer::new());
+        let (left, top) = display.output_position(0, 0);
+        let (right, bottom) = display.output_position(LORES_WIDTH - 1, LORES_HEIGHT - 1);
+
+        // The sprite is on the left of the top row
+        display.set_orientation(true, false);
+        let collision = display.draw(0, 0, &[0xC0]);
+        display.present();
+        assert!(!collision.any());
+        assert!(display.pixel(0, 0) && display.pixel(1, 0));
+        assert_eq!(display.output_position(0, 0), (right, top));
+        assert_eq!(display.output_position(LORES_WIDTH, 0), (right, top));
+        assert_eq!(display.renderer().pixels[top][right], Color::White);
+        assert_eq!(display.renderer().pixels[top][left], Color::Black);
+
+        display.set_orientation(false, true);
+        display.present();
+        assert_eq!(display.output_position(0, 0), (left, bottom));
+        assert_eq!(display.output_position(0, LORES_HEIGHT), (left, bottom));
+        assert_eq!(display.renderer().pixels[bottom][left], Color::White);
+        assert_eq!(display.renderer().pixels[top][left], Color::Black);
+
+        // The collisions are computed on the framebuffer
+        assert!(display.draw(1, 0, &[0x80]).any());
+    }
+
+    #[test]
+    fn classic_xor_toggles_between_bg_and_fg() {
+        let mut display = Display::with_renderer(Color::White, BufferRenderer::new());
+        display.set_colors(Color::Green, Color::Blue);
+        let (x, y) = display.output_position(3, 2);
+
+        let collision = display.draw(3, 2, &[0x80]);
+        display.present();
+        assert!(!collision.any());
+        assert_eq!(display.color_index(3, 2), 1);
+        assert_eq!(display.renderer().pixels[y][x], Color::Green);
+
+        let collision = display.draw(3, 2, &[0x80]);
+        display.present();
+        assert!(collision.any());
+        assert_eq!(display.color_index(3, 2), 0);
+        assert_eq!(display.renderer().pixels[y][x], Color::Blue);
+    }
+
+    #[test]
+    fn packs_the_screen_into_a_bitmap() {
+        let mut display = display();
+        for y in 0..LORES_HEIGHT {
+            for x in (0..LORES_WIDTH).step_by(8) {
+                display.draw(x, y, &[0xAA >> (y % 2)]);
+            }
+        }
+        assert!(display.pixel(0, 0) && !display.pixel(1, 0));
+        assert!(!display.pixel(0, 1) && display.pixel(1, 1));
+
+        // The leftmost pixel is the most significant bit
+        let bitmap = display.as_bitmap();
+        assert_eq!(bitmap[..8], [0xAA; 8]);
+        assert_eq!(bitmap[8..16], [0x55; 8]);
+        assert_eq!(bitmap[BITMAP_SIZE - 1], 0x55);
+
+        let mut restored = Display::with_renderer(Color::White, NullRenderer);
+        restored.load_bitmap(&bitmap);
+        assert_eq!(restored.pixels(), display.pixels());
+        assert_eq!(restored.as_bitmap()[..], bitmap[..]);
+    }
+
+    #[test]
+    fn clips_or_wraps_at_the_right_edge() {
+        let mut display = display();
+        display.draw(62, 0, &[0xFF]);
+        assert!(display.pixel(62, 0) && display.pixel(63, 0));
+        assert!((0..6).all(|x| !display.pixel(x, 0)));
+
+        display.set_quirks(Quirks { wrap_sprites: true, ..Quirks::new() });
+        display.draw(62, 1, &[0xFF]);
+        assert!(display.pixel(62, 1) && display.pixel(63, 1));
+        assert!((0..6).all(|x| display.pixel(x, 1)));
+        assert!(!display.pixel(6, 1));
+    }
+
+    #[test]
+    fn start_coordinates_wrap_under_both_quirks() {
+        for &wrap_sprites in [false, true].iter() {
+            let mut display = display();
+            display.set_quirks(Quirks { wrap_sprites, ..Quirks::new() });
+            display.draw(70, 40, &[0x80, 0x80]);
+            assert!(display.pixel(6, 8) && display.pixel(6, 9), "wrap_sprites: {}", wrap_sprites);
+            assert_eq!(display.pixels().iter().flatten().filter(|&&on| on).count(), 2);
+        }
+    }
+
+    #[test]
+    fn counts_the_colliding_and_clipped_rows() {
+        let mut display = display();
+        display.set_hires(true);
+        assert_eq!(display.draw(10, 10, &[0xFF; 4]), Collision { rows: 0, clipped_rows: 0 });
+
+        // The last two rows overlap the first sprite
+        assert_eq!(display.draw(12, 12, &[0x81; 4]), Collision { rows: 2, clipped_rows: 0 });
+
+        // Half of the large sprite is below the bottom of the screen
+        let collision = display.draw_large(0, 56, &[0xFF; 32]);
+        assert_eq!(collision, Collision { rows: 0, clipped_rows: 8 });
+        assert!(!collision.any() && display.pixel(15, 63));
+
+        // Drawing it again collides with every visible row
+        assert_eq!(display.draw_large(0, 56, &[0xFF; 32]), Collision { rows: 8, clipped_rows: 8 });
+    }
+
+    #[test]
+    fn scrolls_only_the_selected_planes() {
+        let mut display = display();
+        display.set_hires(true);
+        display.select_planes(0b11);
+        display.draw(8, 0, &[0x80, 0x80]);
+        display.select_planes(0b01);
+
+        display.scroll_down(3);
+        assert!(display.plane(0)[3][8] && !display.plane(0)[0][8]);
+        display.scroll_right();
+        assert!(display.plane(0)[3][12] && !display.plane(0)[3][8]);
+        display.scroll_left();
+        display.scroll_left();
+        assert!(display.plane(0)[3][4] && !display.plane(0)[3][12]);
+        assert!(display.plane(1)[0][8]);
+
+        // Pixels scrolled off the screen are gone
+        display.scroll_down(HIRES_HEIGHT);
+        assert!(!display.plane(0).iter().any(|row| row.iter().any(|&on| on)));
+    }
+
+    #[test]
+    fn centers_the_scaled_image() {
+        let mut display = display();
+        assert_eq!(display.scale(), DEFAULT_SCALE);
+        assert_eq!(display.output_position(0, 0), (0, 20));
+        assert_eq!(display.output_position(63, 31), (315, 175));
+
+        display.set_scale(3);
+        assert_eq!(display.output_position(0, 0), (64, 52));
+        assert_eq!(display.output_position(1, 1), (67, 55));
+
+        // The high resolution pixels are half as big
+        display.set_scale(DEFAULT_SCALE);
+        display.set_hires(true);
+        assert_eq!(display.output_position(0, 0), (32, 36));
+        assert_eq!(display.output_position(127, 63), (286, 162));
+
+        // The scale is clamped so the image fits the output
+        display.set_hires(false);
+        display.set_scale(100);
+        assert_eq!(display.scale(), 5);
+        display.set_scale(0);
+        assert_eq!(display.scale(), 1);
+        assert_eq!(display.output_position(0, 0), (128, 84));
+    }
+
+    #[test]
+    fn an_oversized_scale_never_writes_outside_the_output() {
+        // The recording renderer panics on a write outside of the output
+        let mut display = Display::with_renderer(Color::White, RecordingRenderer::new());
+        display.set_scale(usize::MAX);
+        for &hires in [false, true].iter() {
+            display.set_hires(hires);
+            display.load_pixels(&[[true; HIRES_WIDTH]; HIRES_HEIGHT]);
+            display.present();
+
+            let multiplier = if hires { DEFAULT_SCALE / 2 } else { DEFAULT_SCALE };
+            let (right, bottom) = display.output_position(display.width() - 1, display.height() - 1);
+            assert!(right + multiplier <= BUFFER_WIDTH && bottom + multiplier <= BUFFER_HEIGHT);
+            assert!(display.renderer().written[bottom + multiplier - 1][right + multiplier - 1]);
+        }
+    }
+}
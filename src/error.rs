@@ -0,0 +1,61 @@
+use core::fmt;
+
+use crate::cpu::CpuError;
+
+/// Errors reported by the machine, recoverable so a host can show them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    /// The given opcode is not a valid instruction
+    UnknownOpcode(u16),
+
+    /// CALL was executed while the stack was full
+    StackOverflow,
+
+    /// RET was executed while the stack was empty
+    StackUnderflow,
+
+    /// An instruction tried to access memory past the end of the RAM, starting at the given address
+    MemoryOutOfBounds(u16),
+
+    /// The ROM contains no data
+    EmptyRom,
+
+    /// The ROM doesn't fit in memory, contains the size of the ROM
+    RomTooLarge(usize),
+
+    /// The ROM would be loaded into the area reserved for the interpreter, contains the origin
+    ReservedOrigin(u16),
+
+    /// The save was written in another version of the format, contains the version
+    SaveVersion(u16),
+
+    /// The bytes are not a save, or the save is truncated
+    InvalidSave,
+}
+
+impl From<CpuError> for Chip8Error {
+    fn from(error: CpuError) -> Chip8Error {
+        match error {
+            CpuError::UnknownOpcode(opcode) => Chip8Error::UnknownOpcode(opcode),
+            CpuError::StackOverflow => Chip8Error::StackOverflow,
+            CpuError::StackUnderflow => Chip8Error::StackUnderflow,
+            CpuError::MemoryOutOfBounds(address) => Chip8Error::MemoryOutOfBounds(address),
+        }
+    }
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode(opcode) => write!(f, "unknown opcode {:04X}", opcode),
+            Chip8Error::StackOverflow => write!(f, "stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "stack underflow"),
+            Chip8Error::MemoryOutOfBounds(address) => write!(f, "memory access out of bounds at {:04X}", address),
+            Chip8Error::EmptyRom => write!(f, "the ROM is empty"),
+            Chip8Error::RomTooLarge(size) => write!(f, "the ROM is too large: {} bytes", size),
+            Chip8Error::ReservedOrigin(origin) => write!(f, "the ROM can't be loaded at {:04X}, below 0200", origin),
+            Chip8Error::SaveVersion(version) => write!(f, "unsupported save version {}", version),
+            Chip8Error::InvalidSave => write!(f, "invalid save"),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use crate::display::Framebuffer;
+
+/// Notifications sent by the machine to the host while it is running.
+/// Every method does nothing by default, so a host only implements the events it needs.
+pub trait MachineEvents {
+    /// Called at the end of a 60Hz frame in which the screen changed, right after the frame was presented
+    fn on_draw(&mut self, _framebuffer: &Framebuffer) {}
+
+    /// Called when the beep starts (`true`) or stops (`false`), right after the timers were ticked
+    fn on_sound(&mut self, _playing: bool) {}
+}
+
+/// Ignores every event
+pub struct NoEvents;
+
+impl MachineEvents for NoEvents {}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use crate::chip8::Chip8Machine;
+use crate::color::Color;
+use crate::display::{Framebuffer, DEFAULT_SCALE};
+use crate::error::Chip8Error;
+use crate::renderer::Renderer;
+use crate::vga_13h_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// Reads a ROM file into memory
+pub fn load_rom_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    fs::read(path)
+}
+
+/// Loads the ROM into a headless machine seeded with `seed`, executes `cycles` instructions,
+/// and returns the hash of the resulting framebuffer, see `framebuffer_hash`.
+///
+/// This is meant for regression tests against golden values: a known ROM (e.g. the IBM logo
+/// or an opcode test ROM) is run for a fixed number of cycles, and the hash is compared to the one
+/// recorded earlier, see `tests/rom_suite.rs` for the ROMs and how to regenerate the golden values.
+pub fn run_rom_to_hash(rom: &[u8], cycles: usize, seed: u64) -> Result<u64, Chip8Error> {
+    let mut machine = Chip8Machine::new_headless();
+    machine.seed_rng(seed);
+    machine.run_cycles(rom, cycles)?;
+    Ok(framebuffer_hash(machine.pixels()))
+}
+
+/// 64 bit FNV-1a hash of the pixels of the framebuffer, 8 pixels per byte in row-major order
+pub fn framebuffer_hash(pixels: &Framebuffer) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for row in pixels.iter() {
+        for chunk in row.chunks(8) {
+            let byte = chunk.iter().fold(0u8, |byte, &on| byte << 1 | on as u8);
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+    hash
+}
+
+/// Prints the surface to the terminal, for running the emulator on a normal OS during development.
+///
+/// One character is printed for every `cell` x `cell` pixels of the surface, using the top-left pixel of the cell:
+/// `#` if the pixel is not black, a space otherwise. The cell size should match the scale of the display.
+pub struct TerminalRenderer {
+    /// The surface, indexed by row then column
+    pixels: [[Color; BUFFER_WIDTH]; BUFFER_HEIGHT],
+
+    /// Size of the area of the surface represented by a character
+    cell: usize,
+}
+
+impl TerminalRenderer {
+    /// Creates a renderer matching the default scale of the display
+    pub fn new() -> TerminalRenderer {
+        TerminalRenderer::with_cell(DEFAULT_SCALE)
+    }
+
+    /// Creates a renderer printing one character for every `cell` x `cell` pixels
+    pub fn with_cell(cell: usize) -> TerminalRenderer {
+        TerminalRenderer {
+            pixels: [[Color::Black; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            cell: cell.max(1),
+        }
+    }
+}
+
+impl Default for TerminalRenderer {
+    fn default() -> TerminalRenderer {
+        TerminalRenderer::new()
+    }
+}
+
+impl Renderer for TerminalRenderer {
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y][x] = color;
+    }
+
+    fn present(&mut self) {
+        // The whole frame is built first, so it's written to the terminal at once without flickering
+        let mut frame = String::from("\x1b[H");
+        for row in self.pixels.iter().step_by(self.cell) {
+            for &color in row.iter().step_by(self.cell) {
+                frame.push(if color == Color::Black { ' ' } else { '#' });
+            }
+            frame.push('\n');
+        }
+
+        let mut stdout = io::stdout();
+        // There is nothing sensible to do if the terminal is gone
+        let _ = stdout.write_all(frame.as_bytes());
+        let _ = stdout.flush();
+    }
+}
@@ -0,0 +1,223 @@
+use crate::keyboard::Keyboard;
+
+/// Source of the CHIP-8 key states read by the CPU
+pub trait InputSource {
+    /// Returns true if the given CHIP-8 key is down
+    fn is_down(&self, key: u8) -> bool;
+
+    /// Starts waiting for a key for Fx0A
+    fn begin_wait(&mut self);
+
+    /// Returns the key ending the wait started by `begin_wait`, `None` keeps the CPU waiting.
+    /// With `on_release` the wait ends when the key is released after being pressed, otherwise when it's pressed.
+    fn wait(&mut self, on_release: bool) -> Option<u8>;
+}
+
+impl InputSource for Keyboard {
+    fn is_down(&self, key: u8) -> bool {
+        self.is_pressed(key)
+    }
+
+    fn begin_wait(&mut self) {
+        Keyboard::begin_wait(self)
+    }
+
+    fn wait(&mut self, on_release: bool) -> Option<u8> {
+        self.poll_wait(on_release)
+    }
+}
+
+/// Replays a predefined sequence of key presses, for tests.
+///
+/// The current key of the sequence is down until a wait consumes it, then the next key is pressed.
+/// Once the sequence is over no key is down and waiting never ends.
+pub struct ScriptedInput<'a> {
+    keys: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ScriptedInput<'a> {
+    pub fn new(keys: &'a [u8]) -> ScriptedInput<'a> {
+        ScriptedInput { keys, position: 0 }
+    }
+
+    /// Returns the key which is currently down
+    pub fn current(&self) -> Option<u8> {
+        self.keys.get(self.position).copied()
+    }
+
+    /// Returns true if every key of the sequence was consumed
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.keys.len()
+    }
+}
+
+impl<'a> InputSource for ScriptedInput<'a> {
+    fn is_down(&self, key: u8) -> bool {
+        self.current() == Some(key)
+    }
+
+    fn begin_wait(&mut self) {}
+
+    fn wait(&mut self, _on_release: bool) -> Option<u8> {
+        let key = self.current()?;
+        self.position += 1;
+        Some(key)
+    }
+}
+
+/// Maximum number of key state changes stored in an `InputLog`
+pub const INPUT_LOG_SIZE: usize = 1024;
+
+/// Size of the serialized header of an `InputLog` in bytes, followed by `INPUT_EVENT_BYTES` bytes per event.
+///
+/// Serialized layout of an input log, every value is stored big-endian:
+///
+/// +--------+------+---------------------------------------------------------+
+/// | Offset | Size | Content                                                 |
+/// +--------+------+---------------------------------------------------------+
+/// |      0 |    8 | Seed of the random number generator                     |
+/// |      8 |    2 | Number of events (n)                                    |
+/// |     10 |  6*n | Events                                                  |
+/// +--------+------+---------------------------------------------------------+
+///
+/// Each event is the number of cycles since the previous event (4 bytes)
+/// followed by the state of the keys (2 bytes, bit n is set if key n is down).
+///
+pub const INPUT_LOG_HEADER_BYTES: usize = 10;
+
+/// Size of a serialized event of an `InputLog` in bytes
+pub const INPUT_EVENT_BYTES: usize = 6;
+
+/// A change of the key states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    /// Number of instructions executed since the recording started when the keys changed
+    pub cycle: u32,
+
+    /// State of the keys from this cycle on, bit n is set if key n is down
+    pub keys: u16,
+}
+
+/// Every change of the key states during a session, along with the seed of the random numbers,
+/// so the session can be replayed deterministically
+#[derive(Clone)]
+pub struct InputLog {
+    seed: u64,
+    events: [InputEvent; INPUT_LOG_SIZE],
+    len: usize,
+}
+
+impl InputLog {
+    /// Creates an empty log of a session using the given random seed
+    pub fn new(seed: u64) -> InputLog {
+        InputLog {
+            seed,
+            events: [InputEvent { cycle: 0, keys: 0 }; INPUT_LOG_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Seed of the random number generator during the session
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The recorded changes, in the order of the cycles
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events[..self.len]
+    }
+
+    /// Returns true if no more changes can be recorded
+    pub fn is_full(&self) -> bool {
+        self.len == INPUT_LOG_SIZE
+    }
+
+    /// Records the state of the keys at the given cycle if it differs from the last recorded state.
+    /// Changes after the log is full are dropped. A cycle before the last recorded one is recorded as the last one,
+    /// so the events stay in order.
+    pub fn record(&mut self, cycle: u32, keys: u16) {
+        let last = if self.len == 0 { InputEvent { cycle: 0, keys: 0 } } else { self.events[self.len - 1] };
+        if keys != last.keys && !self.is_full() {
+            self.events[self.len] = InputEvent { cycle: cycle.max(last.cycle), keys };
+            self.len += 1;
+        }
+    }
+
+    /// Number of bytes written by `to_bytes`
+    pub fn serialized_len(&self) -> usize {
+        INPUT_LOG_HEADER_BYTES + self.len * INPUT_EVENT_BYTES
+    }
+
+    /// Serializes the log into `out`, returns the number of bytes written or `None` if `out` is too short
+    pub fn to_bytes(&self, out: &mut [u8]) -> Option<usize> {
+        let len = self.serialized_len();
+        if out.len() < len {
+            return None;
+        }
+
+        out[0..8].copy_from_slice(&self.seed.to_be_bytes());
+        out[8..10].copy_from_slice(&(self.len as u16).to_be_bytes());
+        let mut previous = 0;
+        for (index, event) in self.events().iter().enumerate() {
+            let offset = INPUT_LOG_HEADER_BYTES + index * INPUT_EVENT_BYTES;
+            let delta = event.cycle.saturating_sub(previous);
+            out[offset..offset + 4].copy_from_slice(&delta.to_be_bytes());
+            out[offset + 4..offset + 6].copy_from_slice(&event.keys.to_be_bytes());
+            previous = event.cycle;
+        }
+
+        Some(len)
+    }
+
+    /// Deserializes a log previously written by `to_bytes`, returns `None` if the bytes are malformed
+    pub fn from_bytes(bytes: &[u8]) -> Option<InputLog> {
+        if bytes.len() < INPUT_LOG_HEADER_BYTES {
+            return None;
+        }
+
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&bytes[0..8]);
+        let len = u16::from_be_bytes([bytes[8], bytes[9]]) as usize;
+        if len > INPUT_LOG_SIZE || bytes.len() < INPUT_LOG_HEADER_BYTES + len * INPUT_EVENT_BYTES {
+            return None;
+        }
+
+        let mut log = InputLog::new(u64::from_be_bytes(seed));
+        let mut cycle = 0;
+        for index in 0..len {
+            let offset = INPUT_LOG_HEADER_BYTES + index * INPUT_EVENT_BYTES;
+            let e = &bytes[offset..offset + INPUT_EVENT_BYTES];
+            cycle = u32::from_be_bytes([e[0], e[1], e[2], e[3]]).saturating_add(cycle);
+            log.events[index] = InputEvent { cycle, keys: u16::from_be_bytes([e[4], e[5]]) };
+        }
+        log.len = len;
+
+        Some(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_log_keeps_the_cycles_in_order() {
+        let mut log = InputLog::new(1);
+        log.record(100, 0b1);
+        log.record(100, 0b1);
+        log.record(40, 0b0);
+        log.record(300, 0b10);
+        assert_eq!(
+            log.events(),
+            &[InputEvent { cycle: 100, keys: 0b1 }, InputEvent { cycle: 100, keys: 0b0 }, InputEvent { cycle: 300, keys: 0b10 }]
+        );
+
+        let mut bytes = [0; INPUT_LOG_HEADER_BYTES + 3 * INPUT_EVENT_BYTES];
+        assert_eq!(log.to_bytes(&mut bytes[..10]), None);
+        assert_eq!(log.to_bytes(&mut bytes), Some(bytes.len()));
+        let loaded = InputLog::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.seed(), 1);
+        assert_eq!(loaded.events(), log.events());
+    }
+}
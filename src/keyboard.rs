@@ -1,22 +1,475 @@
-pub struct Keyboard {}
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+use x86_64::instructions::port::Port;
+
+///
+/// Default mapping of the CHIP-8 hex keypad to PS/2 (set 1) scancodes
+///
+/// +---+---+---+---+       +---+---+---+---+
+/// | 1 | 2 | 3 | C |       | 1 | 2 | 3 | 4 |
+/// +---+---+---+---+       +---+---+---+---+
+/// | 4 | 5 | 6 | D |       | Q | W | E | R |
+/// +---+---+---+---+  <=>  +---+---+---+---+
+/// | 7 | 8 | 9 | E |       | A | S | D | F |
+/// +---+---+---+---+       +---+---+---+---+
+/// | A | 0 | B | F |       | Z | X | C | V |
+/// +---+---+---+---+       +---+---+---+---+
+///
+pub const DEFAULT_MAPPING: [u8; 16] = [
+    0x2D, // 0 - X
+    0x02, // 1 - 1
+    0x03, // 2 - 2
+    0x04, // 3 - 3
+    0x10, // 4 - Q
+    0x11, // 5 - W
+    0x12, // 6 - E
+    0x1E, // 7 - A
+    0x1F, // 8 - S
+    0x20, // 9 - D
+    0x2C, // A - Z
+    0x2E, // B - C
+    0x05, // C - 4
+    0x13, // D - R
+    0x21, // E - F
+    0x2F, // F - V
+];
+
+/// Scancode of the host key requesting a soft reset of the running ROM by default: F5
+pub const DEFAULT_RESET_SCANCODE: u8 = 0x3F;
+
+/// Prefix of the scancodes of the extended keys in scancode set 1, e.g. the arrows
+const EXTENDED_PREFIX: u8 = 0xE0;
+
+const PS2_DATA_PORT: u16 = 0x60;
+const PS2_STATUS_PORT: u16 = 0x64;
+
+/// Suggested number of cycles a key is held in its new state after it changed, see `Keyboard::set_debounce`.
+/// At the default clock this is about 6ms, longer than the bounce of a typical switch.
+pub const DEFAULT_DEBOUNCE_CYCLES: u32 = 4;
+
+/// State of the keys shared by every `Keyboard::from_global()` keyboard, bit n is set if key n is down.
+///
+/// There is no keyboard interrupt handler: `Chip8Machine::run` polls the PS/2 controller from its main loop,
+/// so today the state is only written and read by that loop. The state is atomic so the keys can be updated
+/// through a shared reference, e.g. by a keyboard interrupt handler installed by the host.
+/// Relaxed ordering is used for every access: the bitmask is the only shared data, and the read-modify-write
+/// operations on it are atomic on their own, so no other memory has to be synchronized.
+pub static GLOBAL_KEYS: AtomicU16 = AtomicU16::new(0);
+
+/// Soft reset request shared by the global keyboards, like `GLOBAL_KEYS`
+pub static GLOBAL_RESET: AtomicBool = AtomicBool::new(false);
+
+/// Keys pressed since they were last observed, shared by the global keyboards like `GLOBAL_KEYS`
+pub static GLOBAL_LATCHED: AtomicU16 = AtomicU16::new(0);
+
+pub struct Keyboard {
+    /// Scancode of each CHIP-8 key, indexed by the key
+    mapping: [u8; 16],
+
+    /// State of the keys, bit n is set if key n is down.
+    /// Atomic, so the keys can be updated through a shared reference, e.g. from an interrupt handler.
+    keys: AtomicU16,
+
+    /// Keys pressed since `is_pressed` last observed them, bit n is set if key n was pressed
+    latched: AtomicU16,
+
+    /// A press is seen by `is_pressed` even if the key was released before it was read
+    latch: bool,
+
+    /// Use the `GLOBAL_KEYS`, `GLOBAL_LATCHED` and `GLOBAL_RESET` statics instead of the fields
+    global: bool,
+
+    /// Scancode of the host key requesting a soft reset, `None` if there is no such key
+    reset_scancode: Option<u8>,
+
+    /// The reset key was pressed and the request was not taken yet
+    reset: AtomicBool,
+
+    /// Keys which were already down when waiting for a key started, bit n is set if key n is ignored
+    ignored: u16,
+
+    /// The freshly pressed key, waiting to be released
+    awaited: Option<u8>,
+
+    /// Number of cycles a key is held in its new state after it changed, 0 disables the debouncing
+    debounce: u32,
+
+    /// The debounced state of the keys, bit n is set if key n is down
+    stable: u16,
+
+    /// Number of cycles left until the change of each key is accepted again, indexed by the key
+    hold: [u32; 16],
+
+    /// The previous byte fed by `handle_ps2_byte` was the prefix of an extended key
+    extended: bool,
+}
 
 impl Keyboard {
     pub fn new() -> Keyboard {
-        Keyboard {}
+        Keyboard {
+            mapping: DEFAULT_MAPPING,
+            keys: AtomicU16::new(0),
+            latched: AtomicU16::new(0),
+            latch: false,
+            global: false,
+            reset_scancode: Some(DEFAULT_RESET_SCANCODE),
+            reset: AtomicBool::new(false),
+            ignored: 0,
+            awaited: None,
+            debounce: 0,
+            stable: 0,
+            hold: [0; 16],
+            extended: false,
+        }
+    }
+
+    /// Creates a keyboard using the state in `GLOBAL_KEYS`, shared by every keyboard created this way.
+    /// Whatever feeds the keys and the machine should both use such a keyboard, the mapping is not shared.
+    pub fn from_global() -> Keyboard {
+        Keyboard {
+            global: true,
+            ..Keyboard::new()
+        }
+    }
+
+    /// Sets the scancode of each CHIP-8 key, indexed by the key
+    pub fn set_mapping(&mut self, map: [u8; 16]) {
+        self.mapping = map;
+    }
+
+    /// Sets the scancode of the host key requesting a soft reset, `None` disables the reset key.
+    /// The key should not be mapped to a CHIP-8 key as well.
+    pub fn set_reset_scancode(&mut self, scancode: Option<u8>) {
+        self.reset_scancode = scancode;
+    }
+
+    /// Latches the presses until they are observed by `is_pressed`, e.g. by SKP or SKNP, so a key pressed
+    /// and released between two reads isn't missed when the keyboard is read slowly.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        self.latched_keys().store(0, Ordering::Relaxed);
+    }
+
+    /// Sets the number of cycles a key is held in its new state after it changed, 0 (the default) disables the debouncing.
+    /// Changes of the key during that time are ignored, so a bouncing switch doesn't register as several presses.
+    /// The debounced state is updated by `tick_debounce`, which the machine calls once per executed instruction.
+    pub fn set_debounce(&mut self, cycles: u32) {
+        self.debounce = cycles;
+        self.stable = self.raw_state();
+        self.hold = [0; 16];
+    }
+
+    /// Advances the debouncing by one cycle: the keys which are not held anymore take their current state
+    pub fn tick_debounce(&mut self) {
+        if self.debounce == 0 {
+            return;
+        }
+
+        let raw = self.raw_state();
+        for key in 0..16 {
+            self.hold[key] = self.hold[key].saturating_sub(1);
+            let bit = 1 << key;
+            if self.hold[key] == 0 && (raw ^ self.stable) & bit != 0 {
+                self.stable ^= bit;
+                self.hold[key] = self.debounce;
+            }
+        }
+    }
+
+    /// Returns true once if the reset key was pressed since the last call
+    pub fn take_reset_request(&self) -> bool {
+        self.reset_flag().swap(false, Ordering::Relaxed)
+    }
+
+    /// Updates the state of the CHIP-8 key mapped to the given scancode, or requests a reset for the reset key.
+    /// Scancodes which are not mapped to any key are ignored.
+    pub fn handle_scancode(&self, scancode: u8, pressed: bool) {
+        if pressed && self.reset_scancode == Some(scancode) {
+            self.reset_flag().store(true, Ordering::Relaxed);
+            return;
+        }
+        if let Some(key) = self.mapping.iter().position(|&code| code == scancode) {
+            if pressed {
+                self.press(key as u8);
+            } else {
+                self.release(key as u8);
+            }
+        }
+    }
+
+    /// Updates the keys from a byte sent by the PS/2 keyboard in scancode set 1, see `poll_ps2`:
+    /// the low 7 bits are the scancode, the high bit is set when the key is released.
+    /// Extended keys are ignored, they would be confused with the keys sharing their scancode without the prefix.
+    pub fn handle_ps2_byte(&mut self, byte: u8) {
+        if byte == EXTENDED_PREFIX {
+            self.extended = true;
+            return;
+        }
+        if mem::replace(&mut self.extended, false) {
+            return;
+        }
+        self.handle_scancode(byte & 0x7F, byte & 0x80 == 0);
+    }
+
+    /// Marks the given CHIP-8 key as down, keys above 0xF are ignored
+    pub fn press(&self, key: u8) {
+        if key < 16 {
+            self.keys().fetch_or(1 << key, Ordering::Relaxed);
+            self.latched_keys().fetch_or(1 << key, Ordering::Relaxed);
+        }
+    }
+
+    /// Marks the given CHIP-8 key as up, keys above 0xF are ignored
+    pub fn release(&self, key: u8) {
+        if key < 16 {
+            self.keys().fetch_and(!(1 << key), Ordering::Relaxed);
+        }
+    }
+
+    /// Releases every key, e.g. when the input focus is lost and the release events would never arrive
+    pub fn clear_all(&self) {
+        self.keys().store(0, Ordering::Relaxed);
+        self.latched_keys().store(0, Ordering::Relaxed);
     }
 
-    pub fn is_pressed(&self, _key: u8) -> bool {
-        // TODO
-        false
+    /// Sets the state of all keys at once, bit n is set if key n is down
+    pub fn set_state(&self, keys: u16) {
+        self.keys().store(keys, Ordering::Relaxed);
+        self.latched_keys().store(0, Ordering::Relaxed);
     }
 
-    pub fn is_released(&self, _key: u8) -> bool {
-        // TODO
-        true
+    /// Returns the state of all keys, bit n is set if key n is down. The state is debounced, see `set_debounce`.
+    pub fn state(&self) -> u16 {
+        if self.debounce > 0 { self.stable } else { self.raw_state() }
     }
 
-    pub fn wait_key(&self) -> u8 {
-        // TODO
-        0x00
+    /// Returns true if the given key is down. When latching, a key pressed since the last call is reported
+    /// as down once, even if it was released since.
+    pub fn is_pressed(&self, key: u8) -> bool {
+        if key >= 16 {
+            return false;
+        }
+        let latched = self.latch && self.latched_keys().fetch_and(!(1 << key), Ordering::Relaxed) & (1 << key) != 0;
+        latched || self.state() & (1 << key) != 0
+    }
+
+    pub fn is_released(&self, key: u8) -> bool {
+        !self.is_pressed(key)
+    }
+
+    /// Returns true if any key is down
+    pub fn any_pressed(&self) -> bool {
+        self.state() != 0
+    }
+
+    /// Returns the lowest CHIP-8 key which is currently down
+    pub fn pressed_key(&self) -> Option<u8> {
+        let keys = self.state();
+        (0..16).find(|&key| keys & (1 << key) != 0)
+    }
+
+    /// Starts waiting for a key, the keys which are already down are ignored until they are released
+    pub fn begin_wait(&mut self) {
+        self.ignored = self.state();
+        self.awaited = None;
+    }
+
+    /// Returns the key which was freshly pressed and then released since `begin_wait`,
+    /// or only freshly pressed if `on_release` is false.
+    /// A key held down since before the wait started doesn't count until it's released and pressed again.
+    pub fn poll_wait(&mut self, on_release: bool) -> Option<u8> {
+        let keys = self.state();
+        self.ignored &= keys;
+        match self.awaited {
+            Some(key) if keys & (1 << key) == 0 => {
+                self.awaited = None;
+                Some(key)
+            }
+            Some(_) => None,
+            None => {
+                let fresh = keys & !self.ignored;
+                self.awaited = (0..16).find(|&key| fresh & (1 << key) != 0);
+                if on_release { None } else { self.awaited.take() }
+            }
+        }
+    }
+
+    /// The state of the keys in use
+    fn keys(&self) -> &AtomicU16 {
+        if self.global { &GLOBAL_KEYS } else { &self.keys }
+    }
+
+    /// The state of the keys as they were last pressed and released, without debouncing
+    fn raw_state(&self) -> u16 {
+        self.keys().load(Ordering::Relaxed)
+    }
+
+    /// The latched presses in use
+    fn latched_keys(&self) -> &AtomicU16 {
+        if self.global { &GLOBAL_LATCHED } else { &self.latched }
+    }
+
+    /// The reset request in use
+    fn reset_flag(&self) -> &AtomicBool {
+        if self.global { &GLOBAL_RESET } else { &self.reset }
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Keyboard {
+        Keyboard::new()
+    }
+}
+
+/// Returns the next byte sent by the PS/2 keyboard, or `None` if there is none, without waiting.
+/// Bytes sent by a PS/2 mouse are dropped.
+pub fn poll_ps2() -> Option<u8> {
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    let mut data: Port<u8> = Port::new(PS2_DATA_PORT);
+
+    unsafe {
+        // Bit 0 is set while the output buffer is full, bit 5 if the byte came from the mouse
+        let status = status.read();
+        if status & 0x01 == 0 {
+            return None;
+        }
+        let byte = data.read();
+        if status & 0x20 != 0 { None } else { Some(byte) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_scancodes_to_keys() {
+        let mut keyboard = Keyboard::new();
+        // W is key 5 by default
+        keyboard.handle_scancode(0x11, true);
+        assert!(keyboard.is_pressed(0x5));
+        assert_eq!(keyboard.state(), 1 << 0x5);
+        keyboard.handle_scancode(0x11, false);
+        assert!(!keyboard.is_pressed(0x5));
+
+        // Remap key 5 to the space bar
+        let mut mapping = DEFAULT_MAPPING;
+        mapping[0x5] = 0x39;
+        keyboard.set_mapping(mapping);
+        keyboard.handle_scancode(0x11, true);
+        assert_eq!(keyboard.state(), 0);
+        keyboard.handle_scancode(0x39, true);
+        assert_eq!(keyboard.state(), 1 << 0x5);
+
+        // The release code sets the high bit, the extended keys are ignored
+        keyboard.handle_ps2_byte(0x39 | 0x80);
+        assert_eq!(keyboard.state(), 0);
+        keyboard.handle_ps2_byte(0x02);
+        assert_eq!(keyboard.state(), 1 << 0x1);
+        keyboard.handle_ps2_byte(EXTENDED_PREFIX);
+        keyboard.handle_ps2_byte(0x39);
+        assert_eq!(keyboard.state(), 1 << 0x1);
+    }
+
+    #[test]
+    fn a_bouncing_key_registers_once() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_debounce(DEFAULT_DEBOUNCE_CYCLES);
+
+        // Press, release, press, release within the debounce window, then the key stays up
+        let mut presses = 0;
+        let mut down = false;
+        for cycle in 0..12 {
+            match cycle {
+                0 | 2 => keyboard.press(5),
+                1 | 3 => keyboard.release(5),
+                _ => {}
+            }
+            keyboard.tick_debounce();
+            let now = keyboard.state() & (1 << 5) != 0;
+            if now && !down {
+                presses += 1;
+            }
+            down = now;
+        }
+
+        assert_eq!(presses, 1);
+        assert!(!down);
+    }
+
+    #[test]
+    fn queries_the_keys_through_a_single_bitmask() {
+        let keyboard = Keyboard::new();
+        keyboard.press(0x0);
+        keyboard.press(0x5);
+        keyboard.press(0xF);
+        keyboard.press(0x10);
+        assert_eq!(keyboard.state(), 0b1000_0000_0010_0001);
+        assert!(keyboard.is_pressed(0x5) && keyboard.is_pressed(0xF));
+        assert!(keyboard.is_released(0x1) && keyboard.is_released(0x10));
+        assert_eq!(keyboard.pressed_key(), Some(0x0));
+
+        keyboard.release(0x0);
+        assert!(keyboard.is_released(0x0));
+        assert_eq!(keyboard.pressed_key(), Some(0x5));
+
+        keyboard.clear_all();
+        assert_eq!(keyboard.state(), 0);
+        assert!(!keyboard.any_pressed());
+        assert!((0..16).all(|key| keyboard.is_released(key)));
+    }
+
+    #[test]
+    fn a_latched_press_is_seen_once() {
+        for &latch in [false, true].iter() {
+            let mut keyboard = Keyboard::new();
+            keyboard.set_latch(latch);
+
+            // Pressed and released between two polls
+            keyboard.press(0x9);
+            keyboard.release(0x9);
+            assert_eq!(keyboard.is_pressed(0x9), latch, "latch: {}", latch);
+            assert!(!keyboard.is_pressed(0x9));
+            assert!(keyboard.is_released(0x9));
+        }
+
+        // A held key stays down after the latched press was seen
+        let mut keyboard = Keyboard::new();
+        keyboard.set_latch(true);
+        keyboard.press(0x2);
+        assert!(keyboard.is_pressed(0x2) && keyboard.is_pressed(0x2));
+        keyboard.release(0x2);
+        assert!(!keyboard.is_pressed(0x2));
+    }
+
+    #[test]
+    fn a_held_key_does_not_satisfy_the_wait() {
+        for &on_release in [false, true].iter() {
+            let mut keyboard = Keyboard::new();
+            keyboard.press(0x3);
+            keyboard.begin_wait();
+            assert_eq!(keyboard.poll_wait(on_release), None);
+
+            // Releasing and pressing the held key again counts as a fresh press
+            keyboard.release(0x3);
+            assert_eq!(keyboard.poll_wait(on_release), None);
+            keyboard.press(0x3);
+            let pressed = keyboard.poll_wait(on_release);
+            if on_release {
+                assert_eq!(pressed, None);
+                keyboard.release(0x3);
+                assert_eq!(keyboard.poll_wait(on_release), Some(0x3));
+            } else {
+                assert_eq!(pressed, Some(0x3));
+            }
+        }
+
+        // Another key pressed while the first one is held
+        let mut keyboard = Keyboard::new();
+        keyboard.press(0x3);
+        keyboard.begin_wait();
+        keyboard.press(0xA);
+        assert_eq!(keyboard.poll_wait(false), Some(0xA));
     }
 }
@@ -1,14 +1,31 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "std")]
+pub mod asm;
 pub mod color;
 pub mod vga_13h_buffer;
 #[macro_use]
 pub mod vga_text_buffer;
 pub mod chip8;
+pub mod clock;
 pub mod cpu;
+pub mod disasm;
 pub mod display;
+pub mod error;
+pub mod events;
+#[cfg(feature = "std")]
+pub mod host;
+pub mod input;
 pub mod keyboard;
+pub mod quirks;
 pub mod ram;
+pub mod renderer;
+pub mod rom;
+pub mod sound;
+pub mod state;
 
 pub fn hlt_loop() -> ! {
     loop {
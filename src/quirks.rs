@@ -0,0 +1,89 @@
+/// Behaviours which differ between CHIP-8 interpreters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// Sprites drawn past the right or bottom edge wrap around to the opposite side of the screen.
+    /// When disabled the off-screen part of the sprite is clipped.
+    pub wrap_sprites: bool,
+
+    /// 8xy6 (SHR) and 8xyE (SHL) shift Vy and store the result in Vx, like the COSMAC VIP.
+    /// When disabled Vx is shifted in place and Vy is ignored, like SUPER-CHIP.
+    pub shift_uses_vy: bool,
+
+    /// Fx55 and Fx65 leave I pointing after the last register stored or loaded (I = I + x + 1),
+    /// like the COSMAC VIP. When disabled I is left unchanged, like SUPER-CHIP.
+    pub load_store_increments_i: bool,
+
+    /// Bnnn is interpreted as Bxnn: jump to xnn + Vx, like SUPER-CHIP.
+    /// When disabled it jumps to nnn + V0, like the COSMAC VIP.
+    pub jump_uses_vx: bool,
+
+    /// Fx1E sets VF to 1 when I overflows past the end of the memory (0xFFF) and to 0 otherwise, like the Amiga interpreter.
+    /// When disabled VF is left unchanged.
+    pub fx1e_sets_vf: bool,
+
+    /// Dxyn waits for the vertical blank, so at most one sprite is drawn per 60Hz frame, like the COSMAC VIP.
+    /// When disabled sprites are drawn immediately.
+    pub display_wait: bool,
+
+    /// 8xy1 (OR), 8xy2 (AND) and 8xy3 (XOR) reset VF to 0, like the COSMAC VIP.
+    /// When disabled VF is left unchanged, like SUPER-CHIP.
+    pub logic_resets_vf: bool,
+
+    /// Fx0A completes when the pressed key is released, like the COSMAC VIP.
+    /// When disabled it completes as soon as the key is pressed.
+    pub wait_key_on_release: bool,
+}
+
+impl Quirks {
+    /// Creates the default quirks, matching the behaviour expected by the common test ROMs
+    pub fn new() -> Quirks {
+        Quirks {
+            wrap_sprites: false,
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            fx1e_sets_vf: false,
+            display_wait: false,
+            logic_resets_vf: false,
+            wait_key_on_release: true,
+        }
+    }
+
+    /// Packs the quirks into a bitmask, bit n is set if the nth quirk in the order of the fields is enabled
+    pub fn to_bits(&self) -> u16 {
+        [
+            self.wrap_sprites,
+            self.shift_uses_vy,
+            self.load_store_increments_i,
+            self.jump_uses_vx,
+            self.fx1e_sets_vf,
+            self.display_wait,
+            self.logic_resets_vf,
+            self.wait_key_on_release,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (n, &enabled)| bits | (enabled as u16) << n)
+    }
+
+    /// Unpacks the quirks from a bitmask packed by `to_bits`, unknown bits are ignored
+    pub fn from_bits(bits: u16) -> Quirks {
+        let enabled = |n: u16| bits & (1 << n) != 0;
+        Quirks {
+            wrap_sprites: enabled(0),
+            shift_uses_vy: enabled(1),
+            load_store_increments_i: enabled(2),
+            jump_uses_vx: enabled(3),
+            fx1e_sets_vf: enabled(4),
+            display_wait: enabled(5),
+            logic_resets_vf: enabled(6),
+            wait_key_on_release: enabled(7),
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::new()
+    }
+}
@@ -1,16 +1,347 @@
+#[cfg(feature = "std")]
+use core::convert::TryInto;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::boxed::Box;
+#[cfg(feature = "std")]
+use std::vec;
+
+/// The area reserved for the interpreter, the font lives there
+pub const RESERVED: Range<usize> = 0x000..0x200;
+
+/// Size of the classic CHIP-8 memory
+pub const MEMORY_SIZE: usize = 4096;
+
+/// Size of the XO-CHIP memory, addressed by 16 bits
+pub const EXTENDED_MEMORY_SIZE: usize = 65536;
+
+/// Smallest configurable memory size, the reserved area and as much room for the program
+pub const MIN_MEMORY_SIZE: usize = 0x400;
+
+/// Intercepts a read of the program from the given address, `None` falls back to the memory
+pub type ReadHook = fn(address: u16) -> Option<u8>;
+
+/// Intercepts a write of the program to the given address, returns true if the write was handled,
+/// false falls back to the memory
+pub type WriteHook = fn(address: u16, value: u8) -> bool;
+
+/// Buffer backing the memory larger than 4 kb, allocated on demand with the `std` feature.
+/// Without it there is no allocator, the buffer has to be provided, e.g. from a static.
+#[cfg(feature = "std")]
+pub type ExtendedMemory = Box<[u8; EXTENDED_MEMORY_SIZE]>;
+
+/// Buffer backing the memory larger than 4 kb, allocated on demand with the `std` feature.
+/// Without it there is no allocator, the buffer has to be provided, e.g. from a static.
+#[cfg(not(feature = "std"))]
+pub type ExtendedMemory = &'static mut [u8; EXTENDED_MEMORY_SIZE];
+
 pub struct Ram {
-    /// 4 kb of memory
-    pub memory: [u8; 4096]
+    /// The classic 4 kb of memory, used until an extended buffer is attached
+    memory: [u8; MEMORY_SIZE],
+
+    /// The 64 kb XO-CHIP memory, replaces `memory` once attached
+    extended: Option<ExtendedMemory>,
+
+    /// Number of addressable bytes, a power of two
+    size: usize,
+
+    /// Addresses which can't be written by the program
+    write_protect: Option<Range<usize>>,
+
+    /// Memory-mapped I/O of custom peripherals
+    read_hook: Option<ReadHook>,
+    write_hook: Option<WriteHook>,
 }
 
 impl Ram {
     pub fn new() -> Ram {
         Ram {
-            memory: [0; 4096],
+            memory: [0; MEMORY_SIZE],
+            extended: None,
+            size: MEMORY_SIZE,
+            write_protect: None,
+            read_hook: None,
+            write_hook: None,
+        }
+    }
+
+    /// Replaces the beginning of the memory with the given data, the rest of the memory is cleared
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        let storage = self.storage_mut();
+        for byte in storage.iter_mut() {
+            *byte = 0;
+        }
+        storage[..rom.len()].copy_from_slice(rom);
+    }
+
+    /// Copies the data to the memory starting at the given offset, the rest of the memory is left untouched.
+    /// Fails without copying anything if the data doesn't fit entirely in the addressable memory.
+    #[allow(clippy::result_unit_err)]
+    pub fn load_program(&mut self, data: &[u8], offset: usize) -> Result<(), ()> {
+        let end = offset.checked_add(data.len()).ok_or(())?;
+        let destination = self.bytes_mut().get_mut(offset..end).ok_or(())?;
+        destination.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Switches between the classic 4 kb and the XO-CHIP 64 kb memory
+    pub fn set_extended(&mut self, extended: bool) {
+        self.set_size(if extended { EXTENDED_MEMORY_SIZE } else { MEMORY_SIZE });
+    }
+
+    /// Returns true if the memory is larger than the classic 4 kb, like the XO-CHIP 64 kb memory
+    pub fn is_extended(&self) -> bool {
+        self.size > MEMORY_SIZE
+    }
+
+    /// Sets the number of addressable bytes, e.g. for experiments with a constrained memory. The change applies
+    /// immediately, the content of the memory is kept. The size is rounded up to a power of two, so addresses can
+    /// wrap around by masking, and clamped between `MIN_MEMORY_SIZE` and `EXTENDED_MEMORY_SIZE`.
+    ///
+    /// A size above 4 kb needs the extended buffer: it's allocated here with the `std` feature, otherwise
+    /// it has to be attached with `attach_extended` first, or the size is limited to 4 kb.
+    pub fn set_size(&mut self, size: usize) {
+        let size = size.next_power_of_two().clamp(MIN_MEMORY_SIZE, EXTENDED_MEMORY_SIZE);
+        #[cfg(feature = "std")]
+        {
+            if size > MEMORY_SIZE && self.extended.is_none() {
+                let buffer = vec![0; EXTENDED_MEMORY_SIZE].into_boxed_slice();
+                self.attach_extended(buffer.try_into().unwrap());
+            }
+        }
+        self.size = if self.extended.is_some() { size } else { size.min(MEMORY_SIZE) };
+    }
+
+    /// Moves the memory to the given 64 kb buffer, so it can grow past 4 kb with `set_size`.
+    /// The content of the memory is kept, the rest of the buffer is cleared.
+    pub fn attach_extended(&mut self, buffer: ExtendedMemory) {
+        let mut memory = [0; MEMORY_SIZE];
+        memory.copy_from_slice(&self.storage()[..MEMORY_SIZE]);
+        self.extended = Some(buffer);
+
+        let storage = self.storage_mut();
+        storage[..MEMORY_SIZE].copy_from_slice(&memory);
+        for byte in storage[MEMORY_SIZE..].iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    /// Number of addressable bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Largest size `set_size` can apply: 64 kb with the `std` feature or an attached extended buffer, 4 kb otherwise
+    pub fn max_size(&self) -> usize {
+        if cfg!(feature = "std") || self.extended.is_some() {
+            EXTENDED_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        }
+    }
+
+    /// Returns the addressable part of the memory
+    pub fn bytes(&self) -> &[u8] {
+        &self.storage()[..self.size]
+    }
+
+    /// Returns the addressable part of the memory for writing, e.g. to restore a state.
+    /// The write protection doesn't apply.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        let size = self.size;
+        &mut self.storage_mut()[..size]
+    }
+
+    /// The whole buffer backing the memory, including the bytes past `size`
+    fn storage(&self) -> &[u8] {
+        match &self.extended {
+            Some(buffer) => &buffer[..],
+            None => &self.memory,
+        }
+    }
+
+    fn storage_mut(&mut self) -> &mut [u8] {
+        match &mut self.extended {
+            Some(buffer) => &mut buffer[..],
+            None => &mut self.memory,
+        }
+    }
+
+    /// Reads a big-endian word, returns `None` if the word is not entirely in memory
+    pub fn read_u16(&self, address: u16) -> Option<u16> {
+        let i = address as usize;
+        if i + 1 >= self.size() {
+            return None;
+        }
+        let memory = self.bytes();
+        Some((memory[i] as u16) << 8 | (memory[i + 1] as u16))
+    }
+
+    /// Writes a big-endian word, returns false if the word is not entirely in memory.
+    /// This is meant for debuggers and front-ends, the write protection doesn't apply.
+    pub fn write_u16(&mut self, address: u16, value: u16) -> bool {
+        let i = address as usize;
+        if i + 1 >= self.size() {
+            return false;
+        }
+        self.bytes_mut()[i..i + 2].copy_from_slice(&value.to_be_bytes());
+        true
+    }
+
+    /// Returns the given range of the memory, e.g. for a hex view, or `None` if the range is not entirely in memory
+    pub fn dump(&self, range: Range<usize>) -> Option<&[u8]> {
+        self.bytes().get(range)
+    }
+
+    /// Protects the given range of addresses from being written by the program, `None` disables the protection.
+    /// Usually this is `RESERVED`, so a program can't corrupt the font.
+    pub fn set_write_protect(&mut self, range: Option<Range<usize>>) {
+        self.write_protect = range;
+    }
+
+    /// Sets the hooks intercepting the reads and writes of the program, e.g. to map a custom peripheral
+    /// to some addresses. The hooks see every address the program reads with `read` or writes with `write`,
+    /// before the memory does. Instructions and sprites are fetched from the memory directly.
+    pub fn set_hooks(&mut self, read: Option<ReadHook>, write: Option<WriteHook>) {
+        self.read_hook = read;
+        self.write_hook = write;
+    }
+
+    /// Reads a byte from memory on behalf of the program
+    pub fn read(&self, address: usize) -> u8 {
+        if let Some(hook) = self.read_hook {
+            if let Some(value) = hook(address as u16) {
+                return value;
+            }
+        }
+
+        self.bytes()[address]
+    }
+
+    /// Writes a byte to memory on behalf of the program.
+    /// Writes to a write protected address are ignored, in which case false is returned.
+    pub fn write(&mut self, address: usize, value: u8) -> bool {
+        if let Some(hook) = self.write_hook {
+            if hook(address as u16, value) {
+                return true;
+            }
+        }
+
+        if let Some(range) = &self.write_protect {
+            if range.contains(&address) {
+                return false;
+            }
+        }
+
+        self.bytes_mut()[address] = value;
+        true
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Ram {
+        Ram::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::FONT;
+
+    #[test]
+    fn load_program_leaves_the_rest_of_the_memory_untouched() {
+        let mut ram = Ram::new();
+        ram.load_rom(&[0xAA; MEMORY_SIZE]);
+        let program = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(ram.load_program(&program, 0x200), Ok(()));
+        assert_eq!(&ram.bytes()[0x200..0x20A], &program);
+        assert_eq!(ram.bytes()[0x1FF], 0xAA);
+        assert_eq!(ram.bytes()[0x20A], 0xAA);
+
+        assert_eq!(ram.load_program(&program, MEMORY_SIZE - 9), Err(()));
+        assert_eq!(ram.load_program(&program, usize::MAX), Err(()));
+        assert!(ram.bytes()[MEMORY_SIZE - 9..].iter().all(|&byte| byte == 0xAA));
+    }
+
+    #[test]
+    fn classic_memory_ends_at_4kb() {
+        let mut ram = Ram::new();
+        assert_eq!(ram.bytes().len(), MEMORY_SIZE);
+        assert_eq!(ram.read_u16(0xFFE), Some(0));
+        assert_eq!(ram.read_u16(0xFFF), None);
+        assert!(!ram.write_u16(0x1000, 0xABCD));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn extended_memory_keeps_the_content() {
+        let mut ram = Ram::new();
+        ram.load_rom(&[1, 2, 3]);
+        ram.set_extended(true);
+        assert!(ram.is_extended());
+        assert_eq!(ram.bytes().len(), EXTENDED_MEMORY_SIZE);
+        assert_eq!(&ram.bytes()[..4], &[1, 2, 3, 0]);
+
+        assert!(ram.write_u16(0xFFFE, 0xABCD));
+        assert_eq!(ram.read_u16(0xFFFE), Some(0xABCD));
+        assert_eq!(ram.read(0x1000), 0);
+
+        ram.set_extended(false);
+        assert_eq!(ram.read_u16(0xFFFE), None);
+        assert_eq!(&ram.bytes()[..4], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn write_protection_keeps_the_font_intact() {
+        let mut ram = Ram::new();
+        ram.load_rom(&FONT);
+        ram.set_write_protect(Some(RESERVED));
+        assert!(!ram.write(0x100, 0xAA));
+        assert_eq!(ram.read(0x100), 0);
+        for address in 0..FONT.len() {
+            assert!(!ram.write(address, 0xFF));
         }
+        assert_eq!(&ram.bytes()[..80], &FONT[..]);
+        assert!(ram.write(0x200, 0xAA));
+
+        // Debuggers can still write the protected area
+        assert!(ram.write_u16(0x100, 0xABCD));
+        ram.set_write_protect(None);
+        assert!(ram.write(0x102, 0xAA));
+        assert_eq!(ram.bytes()[0x100..0x103], [0xAB, 0xCD, 0xAA]);
     }
 
-    pub fn load_rom(&mut self, rom: &[u8; 4096]) {
-        self.memory = (*rom).clone();
+    #[test]
+    fn hooks_intercept_the_mapped_addresses() {
+        use core::sync::atomic::{AtomicU8, Ordering};
+
+        static PORT: AtomicU8 = AtomicU8::new(0);
+
+        fn read_port(address: u16) -> Option<u8> {
+            if address == 0xE00 { Some(0x42) } else { None }
+        }
+
+        fn write_port(address: u16, value: u8) -> bool {
+            if address == 0xE01 {
+                PORT.store(value, Ordering::Relaxed);
+            }
+            address == 0xE01
+        }
+
+        let mut ram = Ram::new();
+        ram.load_rom(&[0xAA; MEMORY_SIZE]);
+        ram.set_hooks(Some(read_port), Some(write_port));
+        assert_eq!(ram.read(0xE00), 0x42);
+        assert_eq!(ram.read(0xE01), 0xAA);
+
+        assert!(ram.write(0xE01, 7));
+        assert_eq!(PORT.load(Ordering::Relaxed), 7);
+        assert_eq!(ram.bytes()[0xE01], 0xAA);
+        assert!(ram.write(0xE02, 8));
+        assert_eq!(ram.bytes()[0xE02], 8);
+
+        ram.set_hooks(None, None);
+        assert_eq!(ram.read(0xE00), 0xAA);
     }
 }
@@ -0,0 +1,135 @@
+use spin::MutexGuard;
+
+use crate::color::Color;
+use crate::vga_13h_buffer::{self, Writer, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// Output surface of the display.
+///
+/// The surface has the size of the 320x200 VGA buffer, the display takes care of scaling
+/// the CHIP-8 pixels and mapping them to colors before writing them to the renderer.
+pub trait Renderer {
+    /// Called before a batch of writes, e.g. a frame, which ends with `present`.
+    /// Renderers may hold on to resources they need for writing until then.
+    fn begin(&mut self) {}
+
+    /// Sets the pixel at the given x,y coordinates of the surface to the given color
+    fn set(&mut self, x: usize, y: usize, color: Color);
+
+    /// Fills the rectangle of the surface starting at the given x,y coordinates with the given color.
+    /// Renderers should override this when filling an area at once is cheaper than setting it pixel by pixel.
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set(column, row, color);
+            }
+        }
+    }
+
+    /// Called after a complete frame was written to the surface
+    fn present(&mut self);
+}
+
+/// Renders straight into the VGA mode 13h buffer.
+///
+/// The writer is locked once from `begin` to `present`, so a whole frame takes a single lock.
+/// Writes outside of a batch lock the writer for each call.
+pub struct Vga13hRenderer {
+    /// The writer locked by `begin`
+    writer: Option<MutexGuard<'static, Writer>>,
+}
+
+impl Vga13hRenderer {
+    pub fn new() -> Vga13hRenderer {
+        Vga13hRenderer { writer: None }
+    }
+
+    /// Runs `f` with the writer of the batch, or with a freshly locked writer outside of a batch
+    fn with_writer<F: FnOnce(&mut Writer)>(&mut self, f: F) {
+        match &mut self.writer {
+            Some(writer) => f(writer),
+            None => f(&mut vga_13h_buffer::WRITER.lock()),
+        }
+    }
+}
+
+impl Default for Vga13hRenderer {
+    fn default() -> Vga13hRenderer {
+        Vga13hRenderer::new()
+    }
+}
+
+impl Renderer for Vga13hRenderer {
+    fn begin(&mut self) {
+        if self.writer.is_none() {
+            self.writer = Some(vga_13h_buffer::WRITER.lock());
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.with_writer(|writer| writer.write_byte(x as u16, y as u16, color as u8));
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        self.with_writer(|writer| {
+            for row in y..y + height {
+                writer.fill_row(x as u16, row as u16, width, color as u8);
+            }
+        });
+    }
+
+    fn present(&mut self) {
+        // Writes to the VGA buffer are visible immediately, only the writer has to be released
+        self.writer = None;
+    }
+}
+
+/// Discards everything, for headless runs
+pub struct NullRenderer;
+
+impl Renderer for NullRenderer {
+    fn set(&mut self, _x: usize, _y: usize, _color: Color) {}
+
+    fn present(&mut self) {}
+}
+
+/// Captures the surface in memory, for tests
+pub struct BufferRenderer {
+    /// The captured surface, indexed by row then column
+    pub pixels: [[Color; BUFFER_WIDTH]; BUFFER_HEIGHT],
+
+    /// Number of presented frames
+    pub frames: usize,
+}
+
+impl BufferRenderer {
+    pub fn new() -> BufferRenderer {
+        BufferRenderer {
+            pixels: [[Color::Black; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            frames: 0,
+        }
+    }
+}
+
+impl Default for BufferRenderer {
+    fn default() -> BufferRenderer {
+        BufferRenderer::new()
+    }
+}
+
+impl Renderer for BufferRenderer {
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y][x] = color;
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        for row in self.pixels[y..y + height].iter_mut() {
+            for pixel in row[x..x + width].iter_mut() {
+                *pixel = color;
+            }
+        }
+    }
+
+    fn present(&mut self) {
+        self.frames += 1;
+    }
+}
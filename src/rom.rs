@@ -0,0 +1,94 @@
+use core::str;
+
+use crate::quirks::Quirks;
+
+/// Marker at the start of a ROM with a metadata header
+pub const ROM_META_MAGIC: [u8; 4] = *b"C8MD";
+
+/// Size of the fixed part of the metadata header in bytes, followed by the title.
+///
+/// Layout of a ROM with a metadata header, every value is stored big-endian:
+///
+/// +--------+------+---------------------------------------------------------+
+/// | Offset | Size | Content                                                 |
+/// +--------+------+---------------------------------------------------------+
+/// |      0 |    4 | Magic marker, "C8MD"                                    |
+/// |      4 |    2 | Quirks, packed by `Quirks::to_bits`                     |
+/// |      6 |    4 | Recommended clock in Hz, 0 if there is none             |
+/// |     10 |    1 | Length of the title (n)                                 |
+/// |     11 |    n | Title, UTF-8                                            |
+/// |   11+n |      | The raw ROM                                             |
+/// +--------+------+---------------------------------------------------------+
+///
+pub const ROM_META_HEADER_BYTES: usize = 11;
+
+/// Metadata shipped in the header of a ROM: how the ROM should be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomMeta<'a> {
+    /// The quirks the ROM expects
+    pub quirks: Quirks,
+
+    /// The recommended number of cycles per second
+    pub clock_hz: Option<u32>,
+
+    /// The title of the ROM, may be empty
+    pub title: &'a str,
+}
+
+impl<'a> RomMeta<'a> {
+    /// Reads the metadata header of the ROM, returns the metadata and the raw ROM following the header.
+    /// Returns `None` if there is no valid header, in which case the whole input is a raw ROM.
+    pub fn parse(bytes: &'a [u8]) -> Option<(RomMeta<'a>, &'a [u8])> {
+        if bytes.len() < ROM_META_HEADER_BYTES || bytes[0..4] != ROM_META_MAGIC {
+            return None;
+        }
+
+        let quirks = Quirks::from_bits(u16::from_be_bytes([bytes[4], bytes[5]]));
+        let clock_hz = u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+        let title_end = ROM_META_HEADER_BYTES + bytes[10] as usize;
+        let title = str::from_utf8(bytes.get(ROM_META_HEADER_BYTES..title_end)?).ok()?;
+
+        let meta = RomMeta {
+            quirks,
+            clock_hz: if clock_hz == 0 { None } else { Some(clock_hz) },
+            title,
+        };
+        Some((meta, &bytes[title_end..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_headered_rom() {
+        let quirks = Quirks { wrap_sprites: true, ..Quirks::new() };
+        let bits = quirks.to_bits().to_be_bytes();
+        let bytes = [
+            b'C', b'8', b'M', b'D', bits[0], bits[1], 0, 0, 0x02, 0xBC, 3, b'P', b'O', b'N', 0x00, 0xE0, 0x12, 0x00,
+        ];
+
+        let (meta, rom) = RomMeta::parse(&bytes).unwrap();
+        assert_eq!(meta, RomMeta { quirks, clock_hz: Some(700), title: "PON" });
+        assert_eq!(rom, [0x00, 0xE0, 0x12, 0x00]);
+
+        // A clock of 0 means there is no recommendation
+        let bytes = [b'C', b'8', b'M', b'D', 0, 0, 0, 0, 0, 0, 0];
+        let (meta, rom) = RomMeta::parse(&bytes).unwrap();
+        assert_eq!((meta.clock_hz, meta.title), (None, ""));
+        assert!(rom.is_empty());
+    }
+
+    #[test]
+    fn rejects_headerless_or_broken_headers() {
+        // A raw ROM
+        assert_eq!(RomMeta::parse(&[0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F, 0x70, 0x09]), None);
+        // Shorter than the fixed part of the header
+        assert_eq!(RomMeta::parse(b"C8MD"), None);
+        // The title is longer than the input
+        assert_eq!(RomMeta::parse(&[b'C', b'8', b'M', b'D', 0, 0, 0, 0, 0, 0, 5, b'A']), None);
+        // The title is not UTF-8
+        assert_eq!(RomMeta::parse(&[b'C', b'8', b'M', b'D', 0, 0, 0, 0, 0, 0, 1, 0xFF]), None);
+    }
+}
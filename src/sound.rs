@@ -0,0 +1,151 @@
+use x86_64::instructions::port::Port;
+
+use crate::clock::PIT_FREQUENCY;
+
+/// Frequency of the beep played while the sound timer is nonzero
+pub const BEEP_FREQUENCY: u16 = 440;
+
+/// Duty cycle of the beep in percent by default, a square wave
+pub const SQUARE_DUTY: u8 = 50;
+
+/// The XO-CHIP pitch register value playing the tone at its base frequency
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// 2^(n/48) for n in 0..48, multiplied by 10000: the ratios of the steps of the XO-CHIP pitch within an octave
+const PITCH_STEPS: [u32; 48] = [
+    10000, 10145, 10293, 10443, 10595, 10749, 10905, 11064,
+    11225, 11388, 11554, 11722, 11892, 12065, 12241, 12419,
+    12599, 12782, 12968, 13157, 13348, 13543, 13740, 13939,
+    14142, 14348, 14557, 14768, 14983, 15201, 15422, 15646,
+    15874, 16105, 16339, 16577, 16818, 17063, 17311, 17563,
+    17818, 18077, 18340, 18607, 18877, 19152, 19431, 19713,
+];
+
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+
+/// Shape of the waves the PC speaker can play.
+///
+/// The speaker follows the output of PIT channel 2, which can only generate a square wave (mode 3)
+/// or a pulse of one PIT tick per period (mode 2), so other duty cycles can't be played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    /// Half of the period high, the classic beep
+    Square,
+
+    /// A pulse of one PIT tick per period, a thin buzzing tone
+    Pulse,
+}
+
+impl Waveform {
+    /// Returns the waveform closest to the given duty cycle in percent, at most 100.
+    /// A duty cycle and its inverse sound the same, so 10% and 90% are both played as a pulse.
+    pub fn from_duty(duty: u8) -> Waveform {
+        let distance = (duty.min(100) as i32 - SQUARE_DUTY as i32).abs();
+        if distance < 25 { Waveform::Square } else { Waveform::Pulse }
+    }
+
+    /// The PIT command for channel 2, access mode lobyte/hibyte and the mode generating the wave
+    fn pit_command(self) -> u8 {
+        match self {
+            Waveform::Square => 0xB6,
+            Waveform::Pulse => 0xB4,
+        }
+    }
+}
+
+/// Starts playing a square wave with the given frequency on the PC speaker, see `set_tone`
+pub fn start_tone(freq_hz: u16) {
+    set_tone(freq_hz, SQUARE_DUTY);
+}
+
+/// Starts playing a tone with the given frequency and duty cycle in percent on the PC speaker,
+/// until `stop_tone`. The duty cycle selects the closest `Waveform` the speaker can play.
+///
+/// The wave is generated by PIT channel 2, whose output is gated to the speaker through port 0x61.
+pub fn set_tone(freq_hz: u16, duty: u8) {
+    if freq_hz == 0 {
+        stop_tone();
+        return;
+    }
+
+    let divisor = pit_divisor(freq_hz);
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel_2: Port<u8> = Port::new(PIT_CHANNEL_2_PORT);
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+
+    unsafe {
+        command.write(Waveform::from_duty(duty).pit_command());
+        channel_2.write((divisor & 0xFF) as u8);
+        channel_2.write((divisor >> 8) as u8);
+
+        // Enable the timer gate and connect its output to the speaker
+        let value = speaker.read();
+        if value & 0x03 != 0x03 {
+            speaker.write(value | 0x03);
+        }
+    }
+}
+
+/// Returns the PIT reload value producing the given frequency, clamped to the slowest possible rate
+pub fn pit_divisor(freq_hz: u16) -> u16 {
+    (PIT_FREQUENCY / freq_hz.max(1) as u32).min(0xFFFF) as u16
+}
+
+/// Returns the frequency of a tone with the given base frequency played at the given XO-CHIP pitch.
+///
+/// Like the XO-CHIP pitch register, `DEFAULT_PITCH` plays the base frequency and every 48 steps is an octave:
+/// frequency = base * 2^((pitch - 64) / 48).
+pub fn pitch_frequency(base_hz: u16, pitch: u8) -> u16 {
+    let steps = pitch as i32 - DEFAULT_PITCH as i32;
+    let octave = steps.div_euclid(48);
+    let scaled = base_hz as u32 * PITCH_STEPS[steps.rem_euclid(48) as usize] / 10000;
+    let frequency = if octave >= 0 { scaled << octave } else { scaled >> -octave };
+    frequency.min(0xFFFF) as u16
+}
+
+/// Silences the PC speaker
+pub fn stop_tone() {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe {
+        let value = speaker.read();
+        speaker.write(value & 0xFC);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_the_pit_divisor() {
+        assert_eq!(pit_divisor(BEEP_FREQUENCY), 2711);
+        assert_eq!(pit_divisor(1000), 1193);
+        // Below ~18Hz the divisor doesn't fit in 16 bits
+        assert_eq!(pit_divisor(10), 0xFFFF);
+        assert_eq!(pit_divisor(0), 0xFFFF);
+    }
+
+    #[test]
+    fn duty_selects_the_closest_waveform() {
+        assert_eq!(Waveform::from_duty(SQUARE_DUTY), Waveform::Square);
+        assert_eq!(Waveform::from_duty(30), Waveform::Square);
+        assert_eq!(Waveform::from_duty(70), Waveform::Square);
+        assert_eq!(Waveform::from_duty(10), Waveform::Pulse);
+        assert_eq!(Waveform::from_duty(90), Waveform::Pulse);
+        assert_eq!(Waveform::from_duty(255), Waveform::Pulse);
+
+        assert_eq!(Waveform::Square.pit_command(), 0xB6);
+        assert_eq!(Waveform::Pulse.pit_command(), 0xB4);
+    }
+
+    #[test]
+    fn pitch_steps_by_48ths_of_an_octave() {
+        assert_eq!(pitch_frequency(BEEP_FREQUENCY, DEFAULT_PITCH), 440);
+        assert_eq!(pitch_frequency(BEEP_FREQUENCY, DEFAULT_PITCH + 48), 880);
+        assert_eq!(pitch_frequency(BEEP_FREQUENCY, DEFAULT_PITCH - 48), 220);
+        assert_eq!(pitch_frequency(BEEP_FREQUENCY, DEFAULT_PITCH + 12), 523);
+    }
+}
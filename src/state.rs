@@ -0,0 +1,224 @@
+use crate::cpu::HaltReason;
+use crate::display::{Framebuffer, HIRES_HEIGHT, HIRES_WIDTH};
+
+/// Size of a serialized `MachineState` in bytes.
+///
+/// Serialized layout of a machine state, every 16 bit value is stored big-endian:
+///
+/// +--------+------+------------------------------------------------------------+
+/// | Offset | Size | Content                                                    |
+/// +--------+------+------------------------------------------------------------+
+/// |      0 |    2 | I                                                          |
+/// |      2 |    2 | PC                                                         |
+/// |      4 |   16 | V0 through VF                                              |
+/// |     20 |   32 | Stack, 16 entries                                          |
+/// |     52 |    1 | SP                                                         |
+/// |     53 |    1 | DT                                                         |
+/// |     54 |    1 | ST                                                         |
+/// |     55 |    1 | 1 if the high resolution mode is active, 0 otherwise       |
+/// |     56 | 4096 | RAM                                                        |
+/// |   4152 | 1024 | Display, 1 bit per pixel, row-major, MSB is leftmost pixel |
+/// |   5176 | 1024 | XO-CHIP second plane of the display, packed the same way   |
+/// |   6200 |    1 | Bitmask of the selected planes                             |
+/// |   6201 |    1 | XO-CHIP pitch                                              |
+/// |   6202 |    1 | Halt reason: 0 running, 1 jumped to itself, 2 EXIT         |
+/// |   6203 |    1 | 1 if Fx0A is waiting for a key, 0 otherwise                |
+/// |   6204 |    8 | State of the random number generator                       |
+/// |   6212 |    8 | Number of instructions executed since the last reset       |
+/// +--------+------+------------------------------------------------------------+
+///
+pub const STATE_SIZE: usize = CPU_OFFSET + 20;
+
+/// Marker at the start of a save written by `Chip8Machine::to_bytes`
+pub const SAVE_MAGIC: [u8; 4] = *b"C8SV";
+
+/// Version of the save format, saves of other versions are rejected
+pub const SAVE_VERSION: u16 = 2;
+
+/// Size of the header of a save written by `Chip8Machine::to_bytes`, followed by a `MachineState`
+/// and `SAVE_MACHINE_BYTES` bytes of the rest of the machine.
+///
+/// Layout of a save, every value is stored big-endian, S = 10 + `STATE_SIZE`:
+///
+/// +--------+--------+-------------------------------------------------------+
+/// | Offset | Size   | Content                                               |
+/// +--------+--------+-------------------------------------------------------+
+/// |      0 |      4 | Magic marker, "C8SV"                                  |
+/// |      4 |      2 | Version of the format, `SAVE_VERSION`                 |
+/// |      6 |      4 | Length of the whole save                              |
+/// |     10 |  S-10  | The machine state, see `STATE_SIZE`                   |
+/// |      S |      2 | Quirks, packed by `Quirks::to_bits`                   |
+/// |    S+2 |      4 | Clock in Hz                                           |
+/// |    S+6 |      8 | Seed of the random number generator                   |
+/// |   S+14 |      4 | Palette, the VGA index of each color                  |
+/// |   S+18 |      2 | Address of the font                                   |
+/// |   S+20 |     80 | The font                                              |
+/// |  S+100 |      4 | Size of the memory (m)                                |
+/// |  S+104 | m-4096 | The memory past the first 4 kb, if there is more      |
+/// +--------+--------+-------------------------------------------------------+
+///
+pub const SAVE_HEADER_BYTES: usize = 10;
+
+/// Size of the part of a save following the machine state, without the extended memory
+pub const SAVE_MACHINE_BYTES: usize = 104;
+
+const MEMORY_OFFSET: usize = 56;
+const PIXELS_OFFSET: usize = MEMORY_OFFSET + 4096;
+const SECOND_PLANE_OFFSET: usize = PIXELS_OFFSET + PLANE_BYTES;
+const CPU_OFFSET: usize = SECOND_PLANE_OFFSET + PLANE_BYTES;
+
+/// Size of a plane of the display packed 1 bit per pixel
+const PLANE_BYTES: usize = HIRES_WIDTH * HIRES_HEIGHT / 8;
+
+/// A snapshot of the complete machine: the CPU, the RAM and the pixels on the display.
+/// Only the classic 4 kb of the memory is part of the state, the XO-CHIP extended memory is not.
+#[derive(Clone)]
+pub struct MachineState {
+    /// index register
+    pub i: u16,
+
+    /// program counter
+    pub pc: u16,
+
+    /// V0 through VF registers
+    pub v: [u8; 16],
+
+    /// Stack of return addresses
+    pub stack: [u16; 16],
+
+    /// stack pointer
+    pub sp: u8,
+
+    /// Delay timer
+    pub dt: u8,
+
+    /// Sound timer
+    pub st: u8,
+
+    /// SUPER-CHIP high resolution mode is active
+    pub hires: bool,
+
+    /// 4 kb of memory
+    pub memory: [u8; 4096],
+
+    /// Pixels of the display, indexed by row then column
+    pub pixels: Framebuffer,
+
+    /// Pixels of the XO-CHIP second plane of the display
+    pub second_plane: Framebuffer,
+
+    /// Bitmask of the planes selected by Fn01
+    pub selected_planes: u8,
+
+    /// XO-CHIP pitch register
+    pub pitch: u8,
+
+    /// Why the program ended, `None` while it's running
+    pub halt: Option<HaltReason>,
+
+    /// Fx0A is waiting for a key
+    pub waiting_key: bool,
+
+    /// State of the random number generator
+    pub rng: u64,
+
+    /// Number of instructions executed since the last reset
+    pub cycles: u64,
+}
+
+impl MachineState {
+    /// Serializes the state into `out`
+    pub fn to_bytes(&self, out: &mut [u8; STATE_SIZE]) {
+        out[0..2].copy_from_slice(&self.i.to_be_bytes());
+        out[2..4].copy_from_slice(&self.pc.to_be_bytes());
+        out[4..20].copy_from_slice(&self.v);
+        for (index, address) in self.stack.iter().enumerate() {
+            out[20 + index * 2..22 + index * 2].copy_from_slice(&address.to_be_bytes());
+        }
+        out[52] = self.sp;
+        out[53] = self.dt;
+        out[54] = self.st;
+        out[55] = self.hires as u8;
+        out[MEMORY_OFFSET..PIXELS_OFFSET].copy_from_slice(&self.memory);
+        pack_plane(&self.pixels, &mut out[PIXELS_OFFSET..SECOND_PLANE_OFFSET]);
+        pack_plane(&self.second_plane, &mut out[SECOND_PLANE_OFFSET..CPU_OFFSET]);
+        out[CPU_OFFSET] = self.selected_planes;
+        out[CPU_OFFSET + 1] = self.pitch;
+        out[CPU_OFFSET + 2] = match self.halt {
+            None => 0,
+            Some(HaltReason::SelfJump) => 1,
+            Some(HaltReason::Exit) => 2,
+        };
+        out[CPU_OFFSET + 3] = self.waiting_key as u8;
+        out[CPU_OFFSET + 4..CPU_OFFSET + 12].copy_from_slice(&self.rng.to_be_bytes());
+        out[CPU_OFFSET + 12..CPU_OFFSET + 20].copy_from_slice(&self.cycles.to_be_bytes());
+    }
+
+    /// Deserializes a state previously written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8; STATE_SIZE]) -> MachineState {
+        let be_u64 = |offset: usize| {
+            let mut word = [0; 8];
+            word.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_be_bytes(word)
+        };
+        let mut state = MachineState {
+            i: u16::from_be_bytes([bytes[0], bytes[1]]),
+            pc: u16::from_be_bytes([bytes[2], bytes[3]]),
+            v: [0; 16],
+            stack: [0; 16],
+            sp: bytes[52],
+            dt: bytes[53],
+            st: bytes[54],
+            hires: bytes[55] != 0,
+            memory: [0; 4096],
+            pixels: unpack_plane(&bytes[PIXELS_OFFSET..SECOND_PLANE_OFFSET]),
+            second_plane: unpack_plane(&bytes[SECOND_PLANE_OFFSET..CPU_OFFSET]),
+            selected_planes: bytes[CPU_OFFSET],
+            pitch: bytes[CPU_OFFSET + 1],
+            halt: match bytes[CPU_OFFSET + 2] {
+                1 => Some(HaltReason::SelfJump),
+                2 => Some(HaltReason::Exit),
+                _ => None,
+            },
+            waiting_key: bytes[CPU_OFFSET + 3] != 0,
+            rng: be_u64(CPU_OFFSET + 4),
+            cycles: be_u64(CPU_OFFSET + 12),
+        };
+
+        state.v.copy_from_slice(&bytes[4..20]);
+        for (index, address) in state.stack.iter_mut().enumerate() {
+            *address = u16::from_be_bytes([bytes[20 + index * 2], bytes[21 + index * 2]]);
+        }
+        state.memory.copy_from_slice(&bytes[MEMORY_OFFSET..PIXELS_OFFSET]);
+
+        state
+    }
+}
+
+/// Packs a plane 1 bit per pixel into `out`, row-major, the most significant bit is the leftmost pixel
+fn pack_plane(pixels: &Framebuffer, out: &mut [u8]) {
+    for byte in out.iter_mut() {
+        *byte = 0;
+    }
+    for (y, row) in pixels.iter().enumerate() {
+        for (x, &on) in row.iter().enumerate() {
+            if on {
+                let bit = y * HIRES_WIDTH + x;
+                out[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+    }
+}
+
+/// Unpacks a plane packed by `pack_plane`
+fn unpack_plane(bytes: &[u8]) -> Framebuffer {
+    let mut pixels = [[false; HIRES_WIDTH]; HIRES_HEIGHT];
+    for (y, row) in pixels.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let bit = y * HIRES_WIDTH + x;
+            *pixel = bytes[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        }
+    }
+
+    pixels
+}
@@ -26,8 +26,23 @@ impl Writer {
         self.buffer.data[y as usize][x as usize].read()
     }
 
+    /// Writes the byte at the given x,y coordinates, writes outside of the buffer are ignored
     pub fn write_byte(&mut self, x: u16, y: u16, byte: u8) {
-        self.buffer.data[y as usize][x as usize].write(byte);
+        if let Some(pixel) = self.buffer.data.get_mut(y as usize).and_then(|row| row.get_mut(x as usize)) {
+            pixel.write(byte);
+        }
+    }
+
+    /// Fills `len` bytes of the row starting at the given x,y coordinates with the byte.
+    /// The part outside of the buffer is ignored.
+    pub fn fill_row(&mut self, x: u16, y: u16, len: usize, byte: u8) {
+        let row = match self.buffer.data.get_mut(y as usize) {
+            Some(row) if (x as usize) < BUFFER_WIDTH => row,
+            _ => return,
+        };
+        for pixel in row[x as usize..(x as usize + len).min(BUFFER_WIDTH)].iter_mut() {
+            pixel.write(byte);
+        }
     }
 
     pub fn xor_byte(&mut self, x: u16, y: u16, byte: u8) -> bool {
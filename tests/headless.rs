@@ -0,0 +1,30 @@
+#![cfg(feature = "std")]
+
+use chip8::chip8::Chip8Machine;
+use chip8::cpu::HaltReason;
+use chip8::host::framebuffer_hash;
+
+const IBM_LOGO: &[u8] = include_bytes!("../games/IBM.ch8");
+
+#[test]
+fn runs_a_rom_headlessly() {
+    let mut machine = Chip8Machine::new_headless();
+    machine.run_cycles(IBM_LOGO, 1000).unwrap();
+
+    // The program draws the logo, then ends by jumping to itself
+    assert_eq!(machine.halt_reason(), Some(HaltReason::SelfJump));
+    assert!(machine.pixels().iter().any(|row| row.iter().any(|&on| on)));
+}
+
+#[test]
+fn hashes_the_framebuffer_after_1000_cycles() {
+    let hash = || {
+        let mut machine = Chip8Machine::new_headless();
+        machine.run_cycles(IBM_LOGO, 1000).unwrap();
+        framebuffer_hash(machine.pixels())
+    };
+
+    // Same hash as the IBM entry of the ROM suite, on every run
+    assert_eq!(hash(), 0x9C1D_A739_CFD8_1398);
+    assert_eq!(hash(), hash());
+}
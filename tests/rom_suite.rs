@@ -0,0 +1,53 @@
+//! Runs well-known public-domain ROMs from `tests/roms` headlessly for a fixed number of cycles,
+//! and compares the hash of the final framebuffer to the golden values in `GOLDEN`.
+//!
+//! To regenerate the golden values after an intended change of the output, run
+//! `cargo test --features std --test rom_suite -- --nocapture`, check the printed screens by eye,
+//! and copy the printed hashes into `GOLDEN`.
+#![cfg(feature = "std")]
+
+use std::path::Path;
+
+use chip8::chip8::Chip8Machine;
+use chip8::display::{LORES_HEIGHT, LORES_WIDTH};
+use chip8::host::{load_rom_file, run_rom_to_hash};
+
+/// Seed of the random numbers, MAZE draws a random maze
+const SEED: u64 = 1;
+
+/// File name in `tests/roms`, number of executed instructions, hash of the final framebuffer
+const GOLDEN: [(&str, usize, u64); 5] = [
+    ("IBM.ch8", 1000, 0x9C1D_A739_CFD8_1398),
+    ("MAZE.ch8", 2000, 0x0466_04A1_419D_BDD5),
+    ("BRIX.ch8", 5000, 0x392F_D3FD_71B3_1010),
+    ("15PUZZLE.ch8", 5000, 0x7739_6FFC_3ED9_4618),
+    ("TICTAC.ch8", 5000, 0x04E9_8DF2_6489_14A9),
+];
+
+/// Runs the ROM like `run_rom_to_hash` and prints the low resolution screen
+fn print_screen(rom: &[u8], cycles: usize) {
+    let mut machine = Chip8Machine::new_headless();
+    machine.seed_rng(SEED);
+    machine.run_cycles(rom, cycles).unwrap();
+    for row in machine.pixels().iter().take(LORES_HEIGHT) {
+        let line: String = row.iter().take(LORES_WIDTH).map(|&on| if on { '#' } else { '.' }).collect();
+        println!("{}", line);
+    }
+}
+
+#[test]
+fn roms_match_the_golden_framebuffers() {
+    let roms = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms");
+    let mut mismatches = Vec::new();
+    for &(name, cycles, golden) in GOLDEN.iter() {
+        let rom = load_rom_file(roms.join(name)).unwrap();
+        let hash = run_rom_to_hash(&rom, cycles, SEED).unwrap();
+        println!("{} after {} cycles: {:#018X}", name, cycles, hash);
+        print_screen(&rom, cycles);
+        if hash != golden {
+            mismatches.push(name);
+        }
+    }
+
+    assert!(mismatches.is_empty(), "framebuffer hash mismatch: {:?}", mismatches);
+}